@@ -0,0 +1,101 @@
+use crate::device_tree::{Cell, DeviceTree, Property};
+use crate::includes_depot::IncludesDepot;
+use tower_lsp::lsp_types::Url;
+
+/*
+ * Translation of reg addresses to CPU address space: address is translated through ranges
+ * of every bus on the way to the root, same as of_translate_address() does in Linux.
+ */
+
+pub struct Region<'a> {
+    pub cells: Vec<&'a Cell>,
+    pub size: u128,
+    // None if address can't be translated, e.g. some bus has no ranges
+    pub cpu: Option<u128>,
+}
+
+impl Region<'_> {
+    // CPU address range in human readable form, e.g. 0x1000-0x1fff
+    pub fn describe(&self) -> Option<String> {
+        let cpu = self.cpu?;
+        Some(match self.size {
+            0 => format!("{cpu:#x}"),
+            size => format!("{cpu:#x}-{:#x}", cpu.saturating_add(size - 1)),
+        })
+    }
+}
+
+// Value of up to four cells, e.g. <0x1 0x80000000> is 0x180000000
+fn value(cells: &[&Cell], uri: &Url, id: &IncludesDepot) -> Option<u128> {
+    if cells.len() > 4 {
+        return None;
+    }
+    cells.iter().try_fold(0, |acc, x| {
+        Some((acc << 32) | u128::from(x.value(uri, id)?))
+    })
+}
+
+fn cells_count(value: u64) -> Option<usize> {
+    usize::try_from(value).ok()
+}
+
+// Translate address from address space of the bus to CPU address space
+pub fn translate(tree: &DeviceTree, id: &IncludesDepot, bus: usize, address: u128) -> Option<u128> {
+    let mut bus = bus;
+    let mut address = address;
+    while let Some(parent) = tree.node(bus).parent {
+        // No ranges means that bus addresses are not mapped to parent address space
+        let ranges = tree.node(bus).property("ranges")?;
+        let cells = ranges.cells()?;
+
+        // Empty ranges is identity mapping
+        if !cells.is_empty() {
+            let child_cells = cells_count(tree.address_cells(Some(bus), id))?;
+            let parent_cells = cells_count(tree.address_cells(Some(parent), id))?;
+            let size_cells = cells_count(tree.size_cells(Some(bus), id))?;
+            let entry = child_cells + parent_cells + size_cells;
+            if entry == 0 {
+                return None;
+            }
+
+            address = cells.chunks_exact(entry).find_map(|x| {
+                let child = value(&x[..child_cells], &ranges.uri, id)?;
+                let parent = value(&x[child_cells..child_cells + parent_cells], &ranges.uri, id)?;
+                let size = value(&x[child_cells + parent_cells..], &ranges.uri, id)?;
+                (child <= address && address - child < size).then(|| address - child + parent)
+            })?;
+        }
+
+        bus = parent;
+    }
+    Some(address)
+}
+
+// Entries of reg property of the node, None if cells don't form complete entries
+pub fn regions<'a>(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    idx: usize,
+    property: &'a Property,
+) -> Option<Vec<Region<'a>>> {
+    let parent = tree.node(idx).parent?;
+    let cells = property.cells()?;
+    let address_cells = cells_count(tree.address_cells(Some(parent), id))?;
+    let size_cells = cells_count(tree.size_cells(Some(parent), id))?;
+    let entry = address_cells + size_cells;
+    if address_cells == 0 || !cells.len().is_multiple_of(entry) {
+        return None;
+    }
+
+    let mut res = Vec::new();
+    for x in cells.chunks_exact(entry) {
+        let address = value(&x[..address_cells], &property.uri, id)?;
+        let size = value(&x[address_cells..], &property.uri, id)?;
+        res.push(Region {
+            cells: x.to_vec(),
+            size,
+            cpu: translate(tree, id, parent, address),
+        });
+    }
+    Some(res)
+}
@@ -0,0 +1,207 @@
+use crate::diagnostics::{is_macro_use, walk};
+use crate::encoding;
+use crate::encoding::convert_range;
+use crate::includes_depot::IncludesDepot;
+use tower_lsp::lsp_types::{Position, Range, TextEdit, Url};
+use tree_sitter::{Node, Tree};
+
+// Macro uses intersecting the range that are not defined in any file of the component
+pub fn unresolved_macros(
+    uri: &Url,
+    tree: &Tree,
+    text: &str,
+    id: &IncludesDepot,
+    range: Range,
+) -> Vec<(String, Range)> {
+    let mut res = Vec::new();
+
+    walk(tree.root_node(), &mut |node| {
+        let r = convert_range(&node.range());
+        if r.end < range.start || r.start > range.end || !is_macro_use(&node) {
+            return;
+        }
+        let name = node.utf8_text(text.as_bytes()).unwrap();
+        if !id.is_defined(uri, name) {
+            res.push((name.to_string(), r));
+        }
+    });
+
+    res
+}
+
+// Position and text of the edit that adds include directive, it goes after the last top level
+// include, or after /dts-v1/; if there are no includes, or to the beginning of the file
+pub fn include_edit(tree: &Tree, path: &str) -> (Position, String) {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let children: Vec<_> = root.named_children(&mut cursor).collect();
+
+    let include = children
+        .iter()
+        .rev()
+        .find(|x| matches!(x.kind(), "preproc_include" | "dtsi_include"));
+    if let Some(x) = include {
+        // preproc_include range includes trailing newline
+        let end = x.end_position();
+        let row = if end.column == 0 {
+            end.row
+        } else {
+            end.row + 1
+        };
+        let position = Position::new(u32::try_from(row).unwrap(), 0);
+        return (position, format!("#include {path}\n"));
+    }
+
+    if let Some(x) = children.iter().find(|x| x.kind() == "file_version") {
+        let row = x.end_position().row + 1;
+        let position = Position::new(u32::try_from(row).unwrap(), 0);
+        return (position, format!("\n#include {path}\n"));
+    }
+
+    (Position::new(0, 0), format!("#include {path}\n\n"))
+}
+
+// Innermost node that contains the position
+pub fn node_at(tree: &Tree, position: Position) -> Option<Node<'_>> {
+    let point = encoding::point(position);
+    let mut cur = tree.root_node().descendant_for_point_range(point, point);
+    while let Some(x) = cur {
+        if x.kind() == "node" {
+            return Some(x);
+        }
+        cur = x.parent();
+    }
+    None
+}
+
+// Leading whitespace of the line where the node starts
+pub fn indent(node: &Node, text: &str) -> String {
+    let line = text
+        .lines()
+        .nth(node.start_position().row)
+        .unwrap_or_default();
+    line.chars().take_while(|x| x.is_whitespace()).collect()
+}
+
+/*
+ * Ranges that remove the nodes from the text. Nodes separated only by whitespace are removed
+ * together, whole lines are removed only when there is nothing else on them. Otherwise the
+ * whitespace between removed and remaining text goes away with the nodes.
+ */
+pub fn removal_ranges(nodes: &[Node], text: &str) -> Vec<Range> {
+    let mut spans: Vec<(usize, usize)> = Vec::new();
+    for node in nodes {
+        match spans.last_mut() {
+            Some(x) if text[x.1..node.start_byte()].trim().is_empty() => x.1 = node.end_byte(),
+            _ => spans.push((node.start_byte(), node.end_byte())),
+        }
+    }
+
+    let position = |x| encoding::offset_position(Position::new(0, 0), text, x);
+    spans
+        .into_iter()
+        .map(|(start, end)| {
+            let line_start = text[..start].rfind('\n').map_or(0, |x| x + 1);
+            let line_end = text[end..].find('\n').map_or(text.len(), |x| end + x);
+            let before = &text[line_start..start];
+            let after = &text[end..line_end];
+            let (start, end) = match (before.trim().is_empty(), after.trim().is_empty()) {
+                (true, true) => (line_start, (line_end + 1).min(text.len())),
+                (_, false) => (start, end + after.len() - after.trim_start().len()),
+                (false, true) => (start - (before.len() - before.trim_end().len()), end),
+            };
+            Range::new(position(start), position(end))
+        })
+        .collect()
+}
+
+// Properties of the node, as opposed to child nodes or preprocessor directives
+pub fn properties<'a>(node: &Node<'a>) -> Vec<Node<'a>> {
+    let mut cursor = node.walk();
+    node.named_children(&mut cursor)
+        .filter(|x| x.kind() == "property")
+        .collect()
+}
+
+// Switch status between "okay" and "disabled", nodes without status are enabled
+pub fn status_edit(node: &Node, text: &str) -> Option<(String, TextEdit)> {
+    let name = node.child_by_field_name("name")?;
+    if name.utf8_text(text.as_bytes()) == Ok("/") {
+        return None;
+    }
+
+    let props = properties(node);
+    let status = props.iter().find(|x| {
+        x.child_by_field_name("name")
+            .is_some_and(|x| x.utf8_text(text.as_bytes()) == Ok("status"))
+    });
+
+    let Some(status) = status else {
+        // Status goes after the other properties
+        let brace = node.children(&mut node.walk()).find(|x| x.kind() == "{");
+        let anchor = props.last().copied().or(brace)?.end_position();
+        let position = Position::new(
+            u32::try_from(anchor.row).unwrap(),
+            u32::try_from(anchor.column).unwrap(),
+        );
+        let new_text = format!("\n{}\tstatus = \"disabled\";", indent(node, text));
+        let edit = TextEdit::new(Range::new(position, position), new_text);
+        return Some((String::from("Set status to \"disabled\""), edit));
+    };
+
+    let value = status.child_by_field_name("value")?;
+    let new_value = match value.utf8_text(text.as_bytes()).ok()? {
+        "\"okay\"" | "\"ok\"" => "\"disabled\"",
+        "\"disabled\"" => "\"okay\"",
+        _ => return None,
+    };
+    let edit = TextEdit::new(convert_range(&value.range()), new_value.to_string());
+    Some((format!("Set status to {new_value}"), edit))
+}
+
+// Label derived from node name, unit address is added if the name is taken
+pub fn label_name(name: &str, address: Option<&str>, taken: impl Fn(&str) -> bool) -> String {
+    let sanitize = |x: &str| x.to_lowercase().replace(['-', ',', '.', '+'], "_");
+    let mut name = sanitize(name);
+    if name.starts_with(|x: char| x.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    let mut candidates = vec![name.clone()];
+    if let Some(x) = address {
+        candidates.push(format!("{name}_{}", sanitize(x)));
+    }
+
+    let base = candidates.last().unwrap().clone();
+    let numbered = (1..).map(|x| format!("{base}_{x}"));
+    candidates
+        .into_iter()
+        .chain(numbered)
+        .find(|x| !taken(x))
+        .unwrap()
+}
+
+// Edit that adds lines to the end of the top level `&label { ... };` block
+pub fn override_insert(tree: &Tree, text: &str, label: &str, lines: &str) -> Option<TextEdit> {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let node = root.named_children(&mut cursor).find(|x| {
+        x.kind() == "node"
+            && x.child_by_field_name("name")
+                .and_then(|x| x.child_by_field_name("label"))
+                .is_some_and(|x| x.utf8_text(text.as_bytes()) == Ok(label))
+    })?;
+    let brace = node.children(&mut node.walk()).find(|x| x.kind() == "}")?;
+
+    let start = brace.start_position();
+    let line = text.lines().nth(start.row).unwrap_or_default();
+    let (column, new_text) = if line[..start.column].trim().is_empty() {
+        (0, lines.to_string())
+    } else {
+        (start.column, format!("\n{lines}"))
+    };
+    let position = Position::new(
+        u32::try_from(start.row).unwrap(),
+        u32::try_from(column).unwrap(),
+    );
+    Some(TextEdit::new(Range::new(position, position), new_text))
+}
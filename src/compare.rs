@@ -0,0 +1,168 @@
+use crate::device_tree::{CellKind, DeviceTree, Property, Value};
+use crate::fdt;
+use crate::includes_depot::IncludesDepot;
+use tower_lsp::lsp_types::Location;
+
+/*
+ * Source tree is compared with the tree of a compiled blob by bytes the properties are encoded
+ * to, so that macros, expressions and references don't show up as differences. Nodes and
+ * properties generated by dtc itself (phandles, symbols, fixups) are ignored unless the source
+ * defines them too.
+ */
+
+const GENERATED_NODES: [&str; 3] = ["__symbols__", "__fixups__", "__local_fixups__"];
+const GENERATED_PROPERTIES: [&str; 2] = ["phandle", "linux,phandle"];
+
+#[derive(Debug)]
+pub struct Difference {
+    pub path: String,
+    // None if the whole node is missing on one of the sides
+    pub property: Option<String>,
+    pub message: String,
+    // Source of the property or node, closest node in the source if it is only in the blob
+    pub location: Option<Location>,
+    pub source: Option<String>,
+    pub blob: Option<String>,
+}
+
+struct Comparison<'a> {
+    source: &'a DeviceTree,
+    blob: &'a DeviceTree,
+    id: &'a IncludesDepot,
+    res: Vec<Difference>,
+}
+
+// Property value as dtc would decompile it
+fn describe(data: &[u8]) -> String {
+    let values: Vec<_> = fdt::values(data).iter().map(fdt::render_value).collect();
+    values.join(", ")
+}
+
+impl Comparison<'_> {
+    // Phandle that the blob assigned to the node of the tree
+    fn phandle(&self, tree: &DeviceTree, idx: usize) -> Option<u64> {
+        let idx = self.blob.find_path(&tree.path(idx))?;
+        let node = self.blob.node(idx);
+        GENERATED_PROPERTIES
+            .iter()
+            .find_map(|x| node.property(x))?
+            .u32(self.id)
+    }
+
+    // Bytes the property is compiled to, None if some of the values can't be evaluated
+    fn encode(&self, tree: &DeviceTree, property: &Property) -> Option<Vec<u8>> {
+        let mut res = Vec::new();
+        for value in &property.values {
+            match value {
+                Value::String(x) => {
+                    res.extend(fdt::unescape(x));
+                    res.push(0);
+                }
+                Value::Bytes(x) => res.extend(x),
+                Value::Path(x) => {
+                    res.extend(tree.path(tree.resolve(x)?).as_bytes());
+                    res.push(0);
+                }
+                Value::Cells(bits, cells) => {
+                    let width = usize::try_from(bits / 8).ok()?;
+                    for cell in cells {
+                        let value = match &cell.kind {
+                            CellKind::Expression => cell.value(&property.uri, self.id)?,
+                            CellKind::Reference(x) => self.phandle(tree, tree.resolve(x)?)?,
+                        };
+                        res.extend(&value.to_be_bytes()[8 - width.min(8)..]);
+                    }
+                }
+                Value::Other => return None,
+            }
+        }
+        Some(res)
+    }
+
+    fn push(&mut self, path: String, property: Option<&str>, message: &str) -> &mut Difference {
+        self.res.push(Difference {
+            path,
+            property: property.map(str::to_string),
+            message: message.to_string(),
+            location: None,
+            source: None,
+            blob: None,
+        });
+        self.res.last_mut().unwrap()
+    }
+
+    fn properties(&mut self, source: usize, blob: usize) {
+        let (tree, other_tree) = (self.source, self.blob);
+        let path = tree.path(source);
+        let location = tree.node(source).locations.first().cloned();
+
+        for property in &tree.node(source).properties {
+            let name = Some(property.name.as_str());
+            let Some(other) = other_tree.node(blob).property(&property.name) else {
+                let x = self.push(path.clone(), name, "Property is missing in the blob");
+                x.location = Some(Location::new(property.uri.clone(), property.range));
+                continue;
+            };
+
+            let expected = self.encode(tree, property);
+            let actual = self.encode(other_tree, other);
+            if let (Some(expected), Some(actual)) = (expected, actual) {
+                if expected != actual {
+                    let x = self.push(path.clone(), name, "Value differs from the blob");
+                    x.location = Some(Location::new(property.uri.clone(), property.range));
+                    x.source = Some(describe(&expected));
+                    x.blob = Some(describe(&actual));
+                }
+            }
+        }
+
+        for property in &other_tree.node(blob).properties {
+            let name = property.name.as_str();
+            if tree.node(source).property(name).is_some() || GENERATED_PROPERTIES.contains(&name) {
+                continue;
+            }
+            let actual = self.encode(other_tree, property);
+            let x = self.push(path.clone(), Some(name), "Property is only in the blob");
+            x.location.clone_from(&location);
+            x.blob = actual.as_deref().map(describe);
+        }
+    }
+
+    fn node(&mut self, source: usize, blob: usize) {
+        let (tree, other_tree) = (self.source, self.blob);
+        self.properties(source, blob);
+
+        for child in &tree.node(source).children {
+            let node = tree.node(*child);
+            if let Some(x) = other_tree.child(blob, &node.name) {
+                self.node(*child, x);
+            } else {
+                let x = self.push(tree.path(*child), None, "Node is missing in the blob");
+                x.location = node.locations.first().cloned();
+            }
+        }
+
+        let location = tree.node(source).locations.first().cloned();
+        for child in &other_tree.node(blob).children {
+            let name = &other_tree.node(*child).name;
+            let generated = source == DeviceTree::ROOT && GENERATED_NODES.contains(&name.as_str());
+            if tree.child(source, name).is_some() || generated {
+                continue;
+            }
+            let x = self.push(other_tree.path(*child), None, "Node is only in the blob");
+            x.location.clone_from(&location);
+        }
+    }
+}
+
+// Nodes and properties that differ between the source tree and the tree of the blob
+pub fn compare(source: &DeviceTree, blob: &DeviceTree, id: &IncludesDepot) -> Vec<Difference> {
+    let mut comparison = Comparison {
+        source,
+        blob,
+        id,
+        res: Vec::new(),
+    };
+    comparison.node(DeviceTree::ROOT, DeviceTree::ROOT);
+    comparison.res
+}
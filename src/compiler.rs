@@ -0,0 +1,350 @@
+use crate::diagnostics::new_diagnostic;
+use crate::file_depot::INCLUDE_PREFIXES;
+use crate::{error, info, log_message};
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, MessageType, Position, Range, Url};
+
+/*
+ * Top-level files are compiled the way kernel build does it: C preprocessor runs first, and its
+ * output is fed to dtc through stdin. Both of them report problems in `file:line...: message`
+ * form, positions in preprocessed text are mapped back to source files with line markers.
+ */
+
+// Flags kernel passes to C preprocessor for device tree sources
+const CPP_FLAGS: [&str; 5] = [
+    "-nostdinc",
+    "-undef",
+    "-x",
+    "assembler-with-cpp",
+    "-D__DTS__",
+];
+
+// Name dtc uses for the input it reads from stdin
+const STDIN: &str = "<stdin>";
+
+struct Message {
+    file: String,
+    range: Range,
+    severity: DiagnosticSeverity,
+    text: String,
+}
+
+fn number(text: &str) -> Option<u32> {
+    text.parse().ok()
+}
+
+// Position in dtc format, 1-based line and column: `line.column`
+fn dtc_position(text: &str) -> Option<(u32, Option<u32>)> {
+    match text.split_once('.') {
+        Some((line, column)) => Some((number(line)?, Some(number(column)?))),
+        None => Some((number(text)?, None)),
+    }
+}
+
+// Location without file part: `line.column-column`, `line.column-line.column` or `line:column`
+fn parse_location(text: &str) -> Option<Range> {
+    let position = |line: u32, column: Option<u32>| {
+        Position::new(
+            line.saturating_sub(1),
+            column.unwrap_or(1).saturating_sub(1),
+        )
+    };
+
+    // C preprocessor
+    if let Some((line, column)) = text.split_once(':') {
+        let start = position(number(line)?, Some(number(column)?));
+        return Some(Range::new(start, start));
+    }
+
+    // End column of dtc location is the one after the last character
+    let (start, end) = text.split_once('-').unwrap_or((text, text));
+    let (line, column) = dtc_position(start)?;
+    let start = position(line, column);
+    let end = match end.split_once('.') {
+        Some(_) => dtc_position(end).map(|(l, c)| position(l, c))?,
+        None => position(line, number(end).or(column)),
+    };
+    Some(Range::new(start, end.max(start)))
+}
+
+// Problem reported by preprocessor or dtc, lines without location are attached to the file
+fn parse_message(line: &str, default: &str) -> Option<Message> {
+    let (rest, mut severity) = match line.strip_prefix("Error: ") {
+        Some(x) => (x, DiagnosticSeverity::ERROR),
+        None => (line, DiagnosticSeverity::WARNING),
+    };
+
+    let location = rest.split_once(' ').and_then(|(location, text)| {
+        let (file, position) = location.trim_end_matches(':').split_once(':')?;
+        Some((file, parse_location(position)?, text))
+    });
+    let (file, range, text) = match location {
+        Some(x) => x,
+        None if line.starts_with("FATAL ERROR: ") => (
+            default,
+            Range::default(),
+            line.trim_start_matches("FATAL ERROR: "),
+        ),
+        None => return None,
+    };
+
+    let prefixes = [
+        ("fatal error: ", DiagnosticSeverity::ERROR),
+        ("error: ", DiagnosticSeverity::ERROR),
+        ("warning: ", DiagnosticSeverity::WARNING),
+        ("note: ", DiagnosticSeverity::INFORMATION),
+    ];
+    let mut text = text.trim();
+    if let Some((x, s)) = prefixes
+        .iter()
+        .find_map(|(p, s)| Some((text.strip_prefix(p)?, s)))
+    {
+        text = x;
+        severity = *s;
+    } else if text.starts_with("ERROR (") {
+        severity = DiagnosticSeverity::ERROR;
+    }
+
+    Some(Message {
+        file: file.to_string(),
+        range,
+        severity,
+        text: text.to_string(),
+    })
+}
+
+// Source file and 0-based line for 0-based line of preprocessed text, based on markers like
+// `# 12 "file.dtsi" 2`
+fn map_line(preprocessed: &str, line: u32) -> Option<(String, u32)> {
+    let mut current: Option<(&str, u32)> = None;
+    for (i, x) in preprocessed.lines().enumerate() {
+        let marker = x.strip_prefix("# ").and_then(|x| {
+            let (number, rest) = x.split_once(' ')?;
+            let file = rest.strip_prefix('"')?.split('"').next()?;
+            Some((file, self::number(number)?))
+        });
+        if let Some((file, number)) = marker {
+            current = Some((file, number.saturating_sub(1)));
+            continue;
+        }
+        if u32::try_from(i).ok()? == line {
+            return current.map(|(file, number)| (file.to_string(), number));
+        }
+        current = current.map(|(file, number)| (file, number + 1));
+    }
+    None
+}
+
+#[derive(Default)]
+struct Settings {
+    // Compiler is not run unless path to dtc is set
+    dtc: Option<String>,
+    cpp: Option<String>,
+    include_paths: Vec<String>,
+}
+
+struct Data {
+    settings: Settings,
+    // Diagnostics by the top-level file they were reported for
+    results: HashMap<Url, Vec<(Url, Diagnostic)>>,
+}
+
+impl Data {
+    fn new() -> Data {
+        Data {
+            settings: Settings::default(),
+            results: HashMap::new(),
+        }
+    }
+
+    // Files that had diagnostics before or have them now
+    fn set_results(&mut self, uri: &Url, results: Vec<(Url, Diagnostic)>) -> Vec<Url> {
+        let old = self
+            .results
+            .insert(uri.clone(), results)
+            .unwrap_or_default();
+        let mut res: Vec<Url> = old
+            .into_iter()
+            .chain(self.results[uri].iter().cloned())
+            .map(|x| x.0)
+            .collect();
+        res.sort();
+        res.dedup();
+        res
+    }
+
+    fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        let mut res: Vec<Diagnostic> = Vec::new();
+        for (url, diag) in self.results.values().flatten() {
+            if url == uri && !res.contains(diag) {
+                res.push(diag.clone());
+            }
+        }
+        res
+    }
+}
+
+#[derive(Clone)]
+pub struct Compiler {
+    data: Arc<Mutex<Data>>,
+}
+
+impl Compiler {
+    pub fn new() -> Compiler {
+        Compiler {
+            data: Arc::new(Mutex::new(Data::new())),
+        }
+    }
+
+    pub fn configure(&self, dtc: Option<String>, cpp: Option<String>, include_paths: Vec<String>) {
+        self.data.lock().unwrap().settings = Settings {
+            dtc,
+            cpp,
+            include_paths,
+        };
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.data.lock().unwrap().settings.dtc.is_some()
+    }
+
+    // Diagnostics of the last compilation of each top-level file, returns files they changed for
+    pub fn set_results(&self, uri: &Url, results: Vec<(Url, Diagnostic)>) -> Vec<Url> {
+        self.data.lock().unwrap().set_results(uri, results)
+    }
+
+    pub fn diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        self.data.lock().unwrap().diagnostics(uri)
+    }
+
+    // Preprocess and compile the file, returns diagnostics for all files it consists of
+    pub async fn run(
+        &self,
+        root: &Path,
+        file: &Path,
+        defines: &[String],
+    ) -> Vec<(Url, Diagnostic)> {
+        let (dtc, cpp, include_paths) = {
+            let settings = &self.data.lock().unwrap().settings;
+            let Some(dtc) = settings.dtc.clone() else {
+                return Vec::new();
+            };
+            let cpp = settings.cpp.clone().unwrap_or(String::from("cpp"));
+            (dtc, cpp, settings.include_paths.clone())
+        };
+        let include_paths: Vec<_> = if include_paths.is_empty() {
+            INCLUDE_PREFIXES.iter().map(|x| root.join(x)).collect()
+        } else {
+            include_paths.iter().map(|x| root.join(x)).collect()
+        };
+
+        info!("Compiling {}", file.display());
+        let mut command = Command::new(&cpp);
+        command.current_dir(root).args(CPP_FLAGS);
+        for x in &include_paths {
+            command.arg("-I").arg(x);
+        }
+        let output = match command.args(defines).arg(file).output().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to run {cpp}: {e}");
+                return Vec::new();
+            }
+        };
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if !output.status.success() {
+            return Self::messages(root, file, &stderr, "", "cpp");
+        }
+        let preprocessed = String::from_utf8_lossy(&output.stdout).to_string();
+
+        let mut command = Command::new(&dtc);
+        command
+            .current_dir(root)
+            .args(["-I", "dts", "-O", "dtb", "-o", "/dev/null"]);
+        if let Some(x) = file.parent() {
+            command.arg("-i").arg(x);
+        }
+        for x in &include_paths {
+            command.arg("-i").arg(x);
+        }
+        let child = command
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn();
+        let mut child = match child {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to run {dtc}: {e}");
+                return Vec::new();
+            }
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = stdin.write_all(preprocessed.as_bytes()).await {
+                error!("Failed to write to {dtc}: {e}");
+            }
+        }
+        let output = match child.wait_with_output().await {
+            Ok(x) => x,
+            Err(e) => {
+                error!("Failed to run {dtc}: {e}");
+                return Vec::new();
+            }
+        };
+
+        let mut res = Self::messages(root, file, &stderr, "", "cpp");
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        res.append(&mut Self::messages(
+            root,
+            file,
+            &stderr,
+            &preprocessed,
+            "dtc",
+        ));
+        res
+    }
+
+    fn messages(
+        root: &Path,
+        file: &Path,
+        output: &str,
+        preprocessed: &str,
+        source: &str,
+    ) -> Vec<(Url, Diagnostic)> {
+        let default = file.to_string_lossy();
+        let mut res = Vec::new();
+        for x in output.lines().filter_map(|x| parse_message(x, &default)) {
+            let (path, range) = if x.file == STDIN {
+                let Some((path, line)) = map_line(preprocessed, x.range.start.line) else {
+                    continue;
+                };
+                let lines = x.range.end.line - x.range.start.line;
+                let mut range = x.range;
+                range.start.line = line;
+                range.end.line = line + lines;
+                (path, range)
+            } else {
+                (x.file, x.range)
+            };
+
+            // Built-in and command line definitions don't belong to any file
+            let path = root.join(path);
+            let Some(uri) = Some(&path)
+                .filter(|x| x.is_file())
+                .and_then(|x| Url::from_file_path(x).ok())
+            else {
+                continue;
+            };
+            let mut diagnostic = new_diagnostic(range, x.severity, x.text);
+            diagnostic.source = Some(source.to_string());
+            res.push((uri, diagnostic));
+        }
+        res
+    }
+}
@@ -0,0 +1,674 @@
+use crate::encoding::convert_range;
+use crate::expressions;
+use crate::file_depot::FileDepot;
+use crate::includes_depot::IncludesDepot;
+use crate::preprocessor;
+use crate::utils::{
+    include_path, is_header, is_overlay, is_plugin, parse, parse_bytes, parse_integer,
+    range_contains,
+};
+use std::collections::HashMap;
+use tower_lsp::lsp_types::{Location, Position, Range, Url};
+
+/*
+ * Device tree as dtc sees it: all included files are merged in order, overrides
+ * (&label { ... }) are applied to their targets, deleted nodes and properties are removed.
+ * Every node and property keeps track of source locations, so that results of the checks
+ * can be reported back to the files.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Reference {
+    Label(String),
+    Path(String),
+}
+
+impl Reference {
+    fn from_node(node: &tree_sitter::Node, text: &str) -> Option<Reference> {
+        if let Some(label) = node.child_by_field_name("label") {
+            let label = label.utf8_text(text.as_bytes()).ok()?;
+            return Some(Reference::Label(label.to_string()));
+        }
+
+        let path = node.named_child(0)?.utf8_text(text.as_bytes()).ok()?;
+        Some(Reference::Path(path.to_string()))
+    }
+}
+
+impl std::fmt::Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Reference::Label(x) => write!(f, "&{x}"),
+            Reference::Path(x) => write!(f, "&{{{x}}}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum CellKind {
+    Reference(Reference),
+    Expression,
+}
+
+#[derive(Clone, Debug)]
+pub struct Cell {
+    pub kind: CellKind,
+    pub text: String,
+    pub range: Range,
+}
+
+impl Cell {
+    // Numeric value of the cell, macros are expanded as long as they resolve to a number
+    pub fn value(&self, uri: &Url, id: &IncludesDepot) -> Option<u64> {
+        self.evaluate(uri, id).ok()
+    }
+
+    pub fn evaluate(&self, uri: &Url, id: &IncludesDepot) -> Result<u64, expressions::Error> {
+        match self.kind {
+            CellKind::Expression => expressions::evaluate(&self.text, &|x| id.find_macro(uri, x)),
+            CellKind::Reference(_) => Err(expressions::Error::Unsupported),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Cells(u32, Vec<Cell>),
+    String(String),
+    Bytes(Vec<u8>),
+    // Reference outside of cells, expands to the path of the node
+    Path(Reference),
+    // Malformed value
+    Other,
+}
+
+#[derive(Clone, Debug)]
+pub struct Property {
+    pub name: String,
+    pub values: Vec<Value>,
+    pub uri: Url,
+    pub range: Range,
+}
+
+impl Property {
+    // All cells of the property, if the property consists only of 32-bit cell arrays
+    pub fn cells(&self) -> Option<Vec<&Cell>> {
+        let mut res = Vec::new();
+        for value in &self.values {
+            match value {
+                Value::Cells(32, cells) => res.extend(cells),
+                _ => return None,
+            }
+        }
+        Some(res)
+    }
+
+    pub fn strings(&self) -> Vec<&str> {
+        self.values
+            .iter()
+            .filter_map(|x| match x {
+                Value::String(x) => Some(x.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn u32(&self, id: &IncludesDepot) -> Option<u64> {
+        match self.cells()?.as_slice() {
+            [x] => x.value(&self.uri, id),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Node {
+    pub name: String,
+    pub labels: Vec<String>,
+    pub parent: Option<usize>,
+    pub children: Vec<usize>,
+    pub properties: Vec<Property>,
+    pub locations: Vec<Location>,
+}
+
+impl Node {
+    pub fn property(&self, name: &str) -> Option<&Property> {
+        self.properties.iter().find(|x| x.name == name)
+    }
+
+    // Node name without unit address
+    pub fn base_name(&self) -> &str {
+        self.name.split_once('@').map_or(&self.name, |x| x.0)
+    }
+
+    pub fn unit_address(&self) -> Option<&str> {
+        self.name.split_once('@').map(|x| x.1)
+    }
+}
+
+pub struct DeviceTree {
+    nodes: Vec<Node>,
+    labels: HashMap<String, usize>,
+    // Every label in the processed source with the node it is attached to
+    pub label_definitions: Vec<(String, Location, usize)>,
+    // Overlay targets that could not be found in base tree
+    pub unresolved: Vec<(Reference, Location)>,
+}
+
+impl DeviceTree {
+    pub fn build(fd: &FileDepot, id: &IncludesDepot, uri: &Url) -> DeviceTree {
+        let mut builder = Builder {
+            fd,
+            id,
+            stack: Vec::new(),
+            tree: DeviceTree::new(),
+            base: false,
+            plugin: false,
+        };
+
+        // Overlays are applied on top of the base tree
+        if let (true, Some(base)) = (is_overlay(uri), fd.get_overlay_base()) {
+            builder.process_file(&base);
+            builder.base = true;
+        }
+
+        builder.process_file(uri);
+        builder.tree
+    }
+
+    pub const ROOT: usize = 0;
+
+    // Tree with root node only
+    pub fn new() -> DeviceTree {
+        DeviceTree {
+            nodes: vec![Node::default()],
+            labels: HashMap::new(),
+            label_definitions: Vec::new(),
+            unresolved: Vec::new(),
+        }
+    }
+
+    pub fn node(&self, idx: usize) -> &Node {
+        &self.nodes[idx]
+    }
+
+    pub fn find_label(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    pub fn find_path(&self, path: &str) -> Option<usize> {
+        let mut idx = Self::ROOT;
+        for name in path.split('/').filter(|x| !x.is_empty()) {
+            let children = &self.nodes[idx].children;
+            let exact = children.iter().find(|x| self.nodes[**x].name == name);
+            let mut by_base_name = children
+                .iter()
+                .filter(|x| self.nodes[**x].base_name() == name);
+
+            idx = match (exact, by_base_name.next(), by_base_name.next()) {
+                (Some(x), _, _) | (None, Some(x), None) => *x,
+                _ => return None,
+            };
+        }
+        Some(idx)
+    }
+
+    pub fn path(&self, idx: usize) -> String {
+        let mut names = Vec::new();
+        let mut cur = idx;
+        while let Some(parent) = self.nodes[cur].parent {
+            names.push(self.nodes[cur].name.as_str());
+            cur = parent;
+        }
+        names.reverse();
+        format!("/{}", names.join("/"))
+    }
+
+    // Node and all of its parents have status "okay" or no status at all
+    pub fn is_enabled(&self, idx: usize) -> bool {
+        let mut cur = Some(idx);
+        while let Some(x) = cur {
+            let status = self.nodes[x].property("status").map(Property::strings);
+            if let Some(status) = status {
+                if !matches!(status.as_slice(), ["okay" | "ok"]) {
+                    return false;
+                }
+            }
+            cur = self.nodes[x].parent;
+        }
+        true
+    }
+
+    pub fn resolve(&self, reference: &Reference) -> Option<usize> {
+        match reference {
+            Reference::Label(x) => self.find_label(x),
+            Reference::Path(x) => self.find_path(x),
+        }
+    }
+
+    // All nodes reachable from root in depth-first order
+    pub fn nodes(&self) -> Vec<usize> {
+        let mut res = Vec::new();
+        let mut to_visit = vec![Self::ROOT];
+        while let Some(idx) = to_visit.pop() {
+            res.push(idx);
+            to_visit.extend(self.nodes[idx].children.iter().rev());
+        }
+        res
+    }
+
+    // Property defined in the file at the given position, along with its node
+    pub fn property_at(&self, uri: &Url, position: Position) -> Option<(usize, &Property)> {
+        self.nodes().into_iter().find_map(|idx| {
+            self.nodes[idx]
+                .properties
+                .iter()
+                .find(|x| x.uri == *uri && range_contains(&x.range, position))
+                .map(|x| (idx, x))
+        })
+    }
+
+    fn cells_property(&self, idx: Option<usize>, name: &str, id: &IncludesDepot) -> Option<u64> {
+        idx.and_then(|x| self.nodes[x].property(name))
+            .and_then(|x| x.u32(id))
+    }
+
+    // #address-cells of the node, 2 if not specified
+    pub fn address_cells(&self, idx: Option<usize>, id: &IncludesDepot) -> u64 {
+        self.cells_property(idx, "#address-cells", id).unwrap_or(2)
+    }
+
+    // #size-cells of the node, 1 if not specified
+    pub fn size_cells(&self, idx: Option<usize>, id: &IncludesDepot) -> u64 {
+        self.cells_property(idx, "#size-cells", id).unwrap_or(1)
+    }
+
+    // Number of cells given by a property like #interrupt-cells, None if it is not specified
+    pub fn cell_count(&self, idx: usize, name: &str, id: &IncludesDepot) -> Option<usize> {
+        let value = self.cells_property(Some(idx), name, id)?;
+        usize::try_from(value).ok()
+    }
+
+    pub fn child(&self, idx: usize, name: &str) -> Option<usize> {
+        self.nodes[idx]
+            .children
+            .iter()
+            .find(|x| self.nodes[**x].name == name)
+            .copied()
+    }
+
+    pub fn node_mut(&mut self, idx: usize) -> &mut Node {
+        &mut self.nodes[idx]
+    }
+
+    pub fn add_label(&mut self, label: &str, idx: usize) {
+        if !self.nodes[idx].labels.iter().any(|x| x == label) {
+            self.nodes[idx].labels.push(label.to_string());
+        }
+        self.labels.insert(label.to_string(), idx);
+    }
+
+    pub fn add_child(&mut self, parent: usize, name: &str) -> usize {
+        if let Some(x) = self.child(parent, name) {
+            return x;
+        }
+
+        self.nodes.push(Node {
+            name: name.to_string(),
+            parent: Some(parent),
+            ..Default::default()
+        });
+        let idx = self.nodes.len() - 1;
+        self.nodes[parent].children.push(idx);
+        idx
+    }
+
+    pub fn delete_node(&mut self, idx: usize) {
+        let Some(parent) = self.nodes[idx].parent else {
+            return;
+        };
+        self.nodes[parent].children.retain(|x| *x != idx);
+
+        let mut deleted = vec![idx];
+        let mut to_visit = vec![idx];
+        while let Some(x) = to_visit.pop() {
+            to_visit.extend(&self.nodes[x].children);
+            deleted.extend(&self.nodes[x].children);
+        }
+        self.labels.retain(|_, x| !deleted.contains(x));
+    }
+}
+
+struct Builder<'a> {
+    fd: &'a FileDepot,
+    id: &'a IncludesDepot,
+    // Files currently being processed, used to break include loops
+    stack: Vec<Url>,
+    tree: DeviceTree,
+    // Base tree is loaded, so overlay targets are expected to be resolved
+    base: bool,
+    // Currently processed file is an overlay
+    plugin: bool,
+}
+
+impl Builder<'_> {
+    fn process_file(&mut self, uri: &Url) {
+        if self.stack.contains(uri) {
+            return;
+        }
+        let Some(text) = self.fd.get_text(uri) else {
+            return;
+        };
+        let tree = parse(&text);
+        let plugin = std::mem::replace(&mut self.plugin, is_plugin(&tree));
+
+        self.stack.push(uri.clone());
+        self.process_items(&tree.root_node(), uri, &text, None);
+        self.stack.pop();
+        self.plugin = plugin;
+    }
+
+    fn process_items(
+        &mut self,
+        node: &tree_sitter::Node,
+        uri: &Url,
+        text: &str,
+        idx: Option<usize>,
+    ) {
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            match (child.kind(), idx) {
+                ("node", Some(DeviceTree::ROOT))
+                    if self.plugin && self.process_fragment(&child, uri, text) => {}
+                ("node", _) => self.process_node(&child, uri, text, idx),
+                ("property", Some(idx)) => self.process_property(&child, uri, text, idx),
+                ("delete_node", _) => self.process_delete_node(&child, text, idx),
+                ("delete_property", Some(idx)) => {
+                    if let Some(name) = child.child_by_field_name("name") {
+                        let name = name.utf8_text(text.as_bytes()).unwrap();
+                        self.tree.nodes[idx].properties.retain(|x| x.name != name);
+                    }
+                }
+                ("dtsi_include" | "preproc_include", None) => {
+                    let Some(path) = child.child_by_field_name("path") else {
+                        continue;
+                    };
+                    let path = include_path(path.utf8_text(text.as_bytes()).unwrap());
+                    if let Some(new_uri) = self.fd.resolve_include(uri, path) {
+                        if !is_header(&new_uri) {
+                            self.process_file(&new_uri);
+                        }
+                    }
+                }
+                ("preproc_if" | "preproc_ifdef", _) => {
+                    self.process_conditional(&child, uri, text, idx);
+                }
+                ("omit_if_no_ref", _) => self.process_items(&child, uri, text, idx),
+                _ => (),
+            }
+        }
+    }
+
+    // Only active branch is processed, or all of them if it can't be determined
+    fn process_conditional(
+        &mut self,
+        node: &tree_sitter::Node,
+        uri: &Url,
+        text: &str,
+        idx: Option<usize>,
+    ) {
+        let mut branch = Some(*node);
+        while let Some(x) = branch {
+            match preprocessor::is_active(&x, uri, text, self.id) {
+                Some(true) => return self.process_items(&x, uri, text, idx),
+                Some(false) => branch = x.child_by_field_name("alternative"),
+                None => break,
+            }
+        }
+
+        while let Some(x) = branch {
+            self.process_items(&x, uri, text, idx);
+            branch = x.child_by_field_name("alternative");
+        }
+    }
+
+    fn process_node(
+        &mut self,
+        node: &tree_sitter::Node,
+        uri: &Url,
+        text: &str,
+        parent: Option<usize>,
+    ) {
+        let Some(name) = node.child_by_field_name("name") else {
+            return;
+        };
+
+        // Location covers unit address as well, if there is one
+        let mut range = name.range();
+        let mut cursor = node.walk();
+        if let Some(address) = node
+            .children_by_field_name("address", &mut cursor)
+            .find(|x| x.kind() == "unit_address")
+        {
+            range.end_byte = address.end_byte();
+            range.end_point = address.end_position();
+        }
+        let location = Location::new(uri.clone(), convert_range(&range));
+        let idx = if name.kind() == "reference" {
+            let Some(reference) = Reference::from_node(&name, text) else {
+                return;
+            };
+            let Some(idx) = self.tree.resolve(&reference) else {
+                if self.plugin && self.base {
+                    self.tree.unresolved.push((reference, location));
+                }
+                return;
+            };
+            idx
+        } else {
+            let name = full_name(node, text);
+            match parent {
+                None if name == "/" => DeviceTree::ROOT,
+                None => return,
+                Some(parent) => self.tree.add_child(parent, &name),
+            }
+        };
+
+        for label in node.children_by_field_name("label", &mut cursor) {
+            let name = label.utf8_text(text.as_bytes()).unwrap();
+            self.tree.add_label(name, idx);
+            let location = Location::new(uri.clone(), convert_range(&label.range()));
+            self.tree
+                .label_definitions
+                .push((name.to_string(), location, idx));
+        }
+        self.tree.nodes[idx].locations.push(location);
+
+        self.process_items(node, uri, text, Some(idx));
+    }
+
+    // Apply fragment@N { target = <&x>; __overlay__ { ... }; } to the target node
+    fn process_fragment(&mut self, node: &tree_sitter::Node, uri: &Url, text: &str) -> bool {
+        let mut overlay = None;
+        let mut target = None;
+
+        let mut cursor = node.walk();
+        for child in node.named_children(&mut cursor) {
+            let Some(name) = child.child_by_field_name("name") else {
+                continue;
+            };
+            let value = child.child_by_field_name("value");
+            let range = value.unwrap_or(child).range();
+
+            target = match (child.kind(), name.utf8_text(text.as_bytes()).unwrap()) {
+                ("node", "__overlay__") => {
+                    overlay = Some(child);
+                    continue;
+                }
+                ("property", "target") => value
+                    .and_then(|x| x.named_child(0))
+                    .filter(|x| x.kind() == "reference")
+                    .and_then(|x| Reference::from_node(&x, text)),
+                ("property", "target-path") => value
+                    .filter(|x| x.kind() == "string_literal")
+                    .and_then(|x| x.utf8_text(text.as_bytes()).ok())
+                    .map(|x| Reference::Path(x.trim_matches('"').to_string())),
+                _ => continue,
+            }
+            .map(|x| (x, Location::new(uri.clone(), convert_range(&range))));
+        }
+
+        let Some(overlay) = overlay else {
+            return false;
+        };
+
+        let Some((reference, location)) = target else {
+            return true;
+        };
+
+        match self.tree.resolve(&reference) {
+            Some(idx) => self.process_items(&overlay, uri, text, Some(idx)),
+            None if self.base => self.tree.unresolved.push((reference, location)),
+            None => (),
+        }
+        true
+    }
+
+    fn process_delete_node(&mut self, node: &tree_sitter::Node, text: &str, idx: Option<usize>) {
+        let Some(name) = node.child_by_field_name("name") else {
+            return;
+        };
+
+        let target = if name.kind() == "reference" {
+            Reference::from_node(&name, text).and_then(|x| self.tree.resolve(&x))
+        } else {
+            idx.and_then(|idx| self.tree.child(idx, &full_name(node, text)))
+        };
+
+        if let Some(target) = target {
+            self.tree.delete_node(target);
+        }
+    }
+
+    fn process_property(&mut self, node: &tree_sitter::Node, uri: &Url, text: &str, idx: usize) {
+        let Some(name) = node.child_by_field_name("name") else {
+            return;
+        };
+
+        let property = Property {
+            name: name.utf8_text(text.as_bytes()).unwrap().to_string(),
+            values: values(node, text),
+            uri: uri.clone(),
+            range: convert_range(&node.range()),
+        };
+
+        let properties = &mut self.tree.nodes[idx].properties;
+        match properties.iter_mut().find(|x| x.name == property.name) {
+            Some(x) => *x = property,
+            None => properties.push(property),
+        }
+    }
+}
+
+// Width of cells set with /bits/
+pub fn bits(node: &tree_sitter::Node, text: &str) -> u32 {
+    let mut cursor = node.walk();
+    let res = node
+        .children_by_field_name("bits", &mut cursor)
+        .find(|x| x.kind() == "integer_literal")
+        .and_then(|x| parse_integer(x.utf8_text(text.as_bytes()).unwrap()))
+        .and_then(|x| u32::try_from(x).ok())
+        .unwrap_or(32);
+    res
+}
+
+// Widths of cells that dtc supports
+pub fn valid_bits(bits: u32) -> bool {
+    matches!(bits, 8 | 16 | 32 | 64)
+}
+
+// Values of property syntax node
+pub fn values(node: &tree_sitter::Node, text: &str) -> Vec<Value> {
+    let bits = bits(node, text);
+    let mut res = Vec::new();
+    let mut cursor = node.walk();
+    for value in node.children_by_field_name("value", &mut cursor) {
+        let source = value.utf8_text(text.as_bytes()).unwrap();
+        res.push(match value.kind() {
+            "integer_cells" => Value::Cells(bits, cells(&value, text)),
+            "string_literal" => Value::String(source.trim_matches('"').to_string()),
+            "byte_string_literal" => parse_bytes(source).map_or(Value::Other, Value::Bytes),
+            "reference" => Reference::from_node(&value, text).map_or(Value::Other, Value::Path),
+            "," => continue,
+            _ => Value::Other,
+        });
+    }
+    res
+}
+
+// Node name including unit address
+fn full_name(node: &tree_sitter::Node, text: &str) -> String {
+    let name = node
+        .child_by_field_name("name")
+        .and_then(|x| x.utf8_text(text.as_bytes()).ok())
+        .unwrap_or_default();
+
+    let mut cursor = node.walk();
+    let address = node
+        .children_by_field_name("address", &mut cursor)
+        .find(|x| x.kind() == "unit_address");
+
+    match address {
+        Some(x) => format!("{name}@{}", x.utf8_text(text.as_bytes()).unwrap()),
+        None => name.to_string(),
+    }
+}
+
+// Split integer cells into separate values, expressions in parentheses are kept as single cell
+pub fn cells(node: &tree_sitter::Node, text: &str) -> Vec<Cell> {
+    let mut res = Vec::new();
+    let mut depth = 0;
+    let mut start = None;
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        match child.kind() {
+            "<" | ">" | "comment" => (),
+            "(" => {
+                if depth == 0 {
+                    start = Some(child);
+                }
+                depth += 1;
+            }
+            ")" => {
+                depth -= 1;
+                if let (0, Some(start)) = (depth, start) {
+                    let range = tree_sitter::Range {
+                        start_byte: start.start_byte(),
+                        end_byte: child.end_byte(),
+                        start_point: start.start_position(),
+                        end_point: child.end_position(),
+                    };
+                    res.push(Cell {
+                        kind: CellKind::Expression,
+                        text: text[range.start_byte..range.end_byte].to_string(),
+                        range: convert_range(&range),
+                    });
+                }
+            }
+            _ if depth > 0 => (),
+            kind => {
+                let reference = match kind {
+                    "reference" => Reference::from_node(&child, text),
+                    _ => None,
+                };
+                res.push(Cell {
+                    kind: reference.map_or(CellKind::Expression, CellKind::Reference),
+                    text: child.utf8_text(text.as_bytes()).unwrap().to_string(),
+                    range: convert_range(&child.range()),
+                });
+            }
+        }
+    }
+    res
+}
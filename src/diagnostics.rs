@@ -1,7 +1,14 @@
-use tower_lsp::lsp_types::{Diagnostic, Url};
+use tower_lsp::lsp_types::{
+    Diagnostic, DiagnosticRelatedInformation, DiagnosticSeverity, DiagnosticTag, Range, Url,
+};
 use tree_sitter::{Node, Tree};
 
-use crate::{includes_depot::IncludesDepot, utils::convert_range};
+use crate::device_tree;
+use crate::device_tree::{CellKind, DeviceTree};
+use crate::expressions;
+use crate::labels_depot::LabelsDepot;
+use crate::utils::{is_plugin, parse_bytes};
+use crate::{encoding::convert_range, includes_depot::IncludesDepot};
 
 pub struct DiagnosticExt {
     pub diag: Diagnostic,
@@ -67,13 +74,13 @@ impl DiagnosticExt {
             return true;
         }
 
-        if id.find_define(&self.url, &self.label).is_some() {
+        if id.is_defined(&self.url, &self.label) {
             return false;
         }
 
         if let Some(parent) = &self.parent {
             if let Some((parent, _)) = parent.split_once('(') {
-                if id.find_define(&self.url, parent).is_some() {
+                if id.is_defined(&self.url, parent) {
                     return false;
                 }
             }
@@ -82,3 +89,232 @@ impl DiagnosticExt {
         true
     }
 }
+
+pub fn new_diagnostic(range: Range, severity: DiagnosticSeverity, message: String) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(severity),
+        source: Some("dts-lsp".to_string()),
+        message,
+        ..Default::default()
+    }
+}
+
+// Code skipped by preprocessor, editors usually dim it
+pub fn inactive_region(range: Range) -> Diagnostic {
+    Diagnostic {
+        tags: Some(vec![DiagnosticTag::UNNECESSARY]),
+        ..new_diagnostic(
+            range,
+            DiagnosticSeverity::HINT,
+            String::from("Inactive preprocessor block"),
+        )
+    }
+}
+
+pub fn walk<'a>(node: Node<'a>, f: &mut impl FnMut(Node<'a>)) {
+    f(node);
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        walk(child, f);
+    }
+}
+
+// Identifier inside of cells that must be expanded by preprocessor. Arguments of macro calls are
+// skipped, since they may be consumed by token pasting and not be macros themselves.
+pub fn is_macro_use(node: &Node) -> bool {
+    if node.kind() != "identifier" {
+        return false;
+    }
+
+    let mut cur = *node;
+    while let Some(parent) = cur.parent() {
+        match parent.kind() {
+            "integer_cells" => return true,
+            "call_expression" if parent.child_by_field_name("function") != Some(cur) => {
+                return false
+            }
+            "binary_expression"
+            | "unary_expression"
+            | "parenthesized_expression"
+            | "conditional_expression"
+            | "call_expression" => (),
+            _ => return false,
+        }
+        cur = parent;
+    }
+    false
+}
+
+// Reference that is used as a target of override block or overlay fragment
+fn is_overlay_target(node: &Node, text: &str) -> bool {
+    let Some(reference) = node.parent() else {
+        return false;
+    };
+    let Some(parent) = reference.parent() else {
+        return false;
+    };
+
+    match parent.kind() {
+        "node" => parent.child_by_field_name("name") == Some(reference),
+        "integer_cells" => parent
+            .parent()
+            .and_then(|x| x.child_by_field_name("name"))
+            .is_some_and(|x| x.utf8_text(text.as_bytes()) == Ok("target")),
+        _ => false,
+    }
+}
+
+pub fn undefined_symbols(
+    url: &Url,
+    tree: &Tree,
+    text: &str,
+    ld: &LabelsDepot,
+    id: &IncludesDepot,
+    check_labels: bool,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    // Overlay targets are checked against merged tree
+    let plugin = is_plugin(tree);
+
+    walk(tree.root_node(), &mut |node| {
+        let range = convert_range(&node.range());
+        let name = node.utf8_text(text.as_bytes()).unwrap();
+
+        if node.kind() == "identifier" && node.parent().map(|x| x.kind()) == Some("reference") {
+            if !check_labels || (plugin && is_overlay_target(&node, text)) {
+                return;
+            }
+            if ld.find_label(url, name).is_empty() {
+                let msg = format!("Undefined label: {name}");
+                diagnostics.push(new_diagnostic(range, DiagnosticSeverity::ERROR, msg));
+            }
+        } else if is_macro_use(&node) && !id.is_defined(url, name) {
+            let msg = format!("Unresolved macro: {name}");
+            diagnostics.push(new_diagnostic(range, DiagnosticSeverity::ERROR, msg));
+        }
+    });
+
+    diagnostics
+}
+
+// Values that dtc rejects: unsupported cell widths, numbers that don't fit into cells, division
+// by zero, references in cells narrower than 32 bits and malformed byte strings
+pub fn invalid_values(url: &Url, tree: &Tree, text: &str, id: &IncludesDepot) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut error = |range: Range, msg: String| {
+        diagnostics.push(new_diagnostic(range, DiagnosticSeverity::ERROR, msg));
+    };
+
+    walk(tree.root_node(), &mut |node| {
+        if node.kind() == "byte_string_literal" {
+            if parse_bytes(node.utf8_text(text.as_bytes()).unwrap()).is_none() {
+                let msg = String::from("Malformed byte string, expected pairs of hex digits");
+                error(convert_range(&node.range()), msg);
+            }
+            return;
+        }
+        if node.kind() == "property" {
+            let mut cursor = node.walk();
+            let width = node
+                .children_by_field_name("bits", &mut cursor)
+                .find(|x| x.kind() == "integer_literal");
+            if let Some(width) = width {
+                if !device_tree::valid_bits(device_tree::bits(&node, text)) {
+                    let msg = String::from("Only 8, 16, 32 and 64-bit cells are supported");
+                    error(convert_range(&width.range()), msg);
+                }
+            }
+            return;
+        }
+        if node.kind() != "integer_cells" {
+            return;
+        }
+
+        // Width of cells is already reported if it is not supported
+        let bits = node.parent().map_or(32, |x| device_tree::bits(&x, text));
+        let valid = device_tree::valid_bits(bits);
+        for cell in device_tree::cells(&node, text) {
+            match cell.evaluate(url, id) {
+                Ok(x) if valid && !expressions::fits(x, bits) => {
+                    error(
+                        cell.range,
+                        format!("Value {x:#x} does not fit in {bits} bits"),
+                    );
+                }
+                Err(
+                    e @ (expressions::Error::DivisionByZero | expressions::Error::Recursive(_)),
+                ) => {
+                    error(cell.range, e.to_string());
+                }
+                Err(_) if valid && bits != 32 && matches!(cell.kind, CellKind::Reference(_)) => {
+                    let msg = format!("References are not allowed in {bits}-bit cells");
+                    error(cell.range, msg);
+                }
+                _ => (),
+            }
+        }
+    });
+
+    diagnostics
+}
+
+// Macros that expand to themselves, directly or through other macros
+pub fn recursive_macros(url: &Url, tree: &Tree, text: &str, id: &IncludesDepot) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    walk(tree.root_node(), &mut |node| {
+        let Some(name) = node
+            .child_by_field_name("name")
+            .filter(|_| node.kind() == "preproc_def")
+        else {
+            return;
+        };
+        let range = convert_range(&name.range());
+        let name = name.utf8_text(text.as_bytes()).unwrap();
+
+        // Only report definitions the lookup resolves to, others are shadowed
+        let is_used = id
+            .find_define(url, name)
+            .is_some_and(|x| x.uri == *url && x.range.start <= range.start);
+        if let (true, Err(e @ expressions::Error::Recursive(_))) = (
+            is_used,
+            expressions::evaluate(name, &|x| id.find_macro(url, x)),
+        ) {
+            diagnostics.push(new_diagnostic(
+                range,
+                DiagnosticSeverity::ERROR,
+                e.to_string(),
+            ));
+        }
+    });
+    diagnostics
+}
+
+// Labels may be defined several times as long as they point to the same node of the merged
+// tree. Labels in code skipped by preprocessor are not part of the tree.
+pub fn duplicate_labels(url: &Url, tree: &DeviceTree) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let definitions = &tree.label_definitions;
+
+    for (name, location, idx) in definitions.iter().filter(|x| x.1.uri == *url) {
+        let others: Vec<DiagnosticRelatedInformation> = definitions
+            .iter()
+            .filter(|x| x.0 == *name && x.2 != *idx)
+            .map(|x| DiagnosticRelatedInformation {
+                location: x.1.clone(),
+                message: format!("label {name} is also defined here"),
+            })
+            .collect();
+
+        if others.is_empty() || diagnostics.iter().any(|x| x.range == location.range) {
+            continue;
+        }
+
+        let msg = format!("Duplicate label: {name}");
+        let mut diag = new_diagnostic(location.range, DiagnosticSeverity::ERROR, msg);
+        diag.related_information = Some(others);
+        diagnostics.push(diag);
+    }
+
+    diagnostics
+}
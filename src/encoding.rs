@@ -0,0 +1,150 @@
+use tower_lsp::lsp_types::{ClientCapabilities, Position, PositionEncodingKind, Range};
+use tree_sitter::Point;
+
+/*
+ * Positions are stored the way tree-sitter reports them, i.e. with byte columns, which is
+ * the same as UTF-8 position encoding. Conversion to encoding negotiated with client is done
+ * only when positions are received from or sent to client.
+ */
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    // Mandatory for all clients, so it's used when nothing else is supported
+    #[default]
+    Utf16,
+}
+
+impl Encoding {
+    // UTF-8 is preferred, since it requires no conversion
+    pub fn negotiate(capabilities: &ClientCapabilities) -> Encoding {
+        let supported = capabilities
+            .general
+            .as_ref()
+            .and_then(|x| x.position_encodings.as_ref());
+
+        match supported {
+            Some(x) if x.contains(&PositionEncodingKind::UTF8) => Encoding::Utf8,
+            _ => Encoding::Utf16,
+        }
+    }
+
+    pub fn kind(self) -> PositionEncodingKind {
+        match self {
+            Encoding::Utf8 => PositionEncodingKind::UTF8,
+            Encoding::Utf16 => PositionEncodingKind::UTF16,
+        }
+    }
+
+    // Length of the text in units of the encoding
+    fn len(self, text: &str) -> usize {
+        match self {
+            Encoding::Utf8 => text.len(),
+            Encoding::Utf16 => text.encode_utf16().count(),
+        }
+    }
+}
+
+fn line(text: &str, n: u32) -> Option<&str> {
+    text.split('\n').nth(n as usize)
+}
+
+// Byte offset of the line start, text length if there is no such line
+fn line_offset(text: &str, n: u32) -> usize {
+    text.split_inclusive('\n')
+        .take(n as usize)
+        .map(str::len)
+        .sum()
+}
+
+// Clamp byte column to the line and move it back to the character boundary
+fn clamp(line: &str, column: usize) -> usize {
+    let mut column = column.min(line.len());
+    while !line.is_char_boundary(column) {
+        column -= 1;
+    }
+    column
+}
+
+// Byte offset of the position with byte column
+pub fn offset(text: &str, position: Position) -> usize {
+    let start = line_offset(text, position.line);
+    let column = line(text, position.line).map_or(0, |x| clamp(x, position.character as usize));
+    start + column
+}
+
+// Range of tree-sitter node, columns are in bytes
+pub fn convert_range(range: &tree_sitter::Range) -> Range {
+    Range::new(
+        Position::new(
+            u32::try_from(range.start_point.row).unwrap(),
+            u32::try_from(range.start_point.column).unwrap(),
+        ),
+        Position::new(
+            u32::try_from(range.end_point.row).unwrap(),
+            u32::try_from(range.end_point.column).unwrap(),
+        ),
+    )
+}
+
+// Position of byte offset inside of the text that starts at the given position
+pub fn offset_position(start: Position, text: &str, offset: usize) -> Position {
+    let prefix = &text[..offset];
+    match prefix.rfind('\n') {
+        Some(x) => Position::new(
+            start.line + u32::try_from(prefix.matches('\n').count()).unwrap(),
+            u32::try_from(offset - x - 1).unwrap(),
+        ),
+        None => Position::new(start.line, start.character + u32::try_from(offset).unwrap()),
+    }
+}
+
+pub fn point(position: Position) -> Point {
+    Point::new(position.line as usize, position.character as usize)
+}
+
+// Convert position with byte column to the client encoding
+pub fn to_client(text: &str, position: Position, encoding: Encoding) -> Position {
+    let Some(line) = line(text, position.line).filter(|_| encoding != Encoding::Utf8) else {
+        return position;
+    };
+    let prefix = &line[..clamp(line, position.character as usize)];
+    let character = u32::try_from(encoding.len(prefix)).unwrap();
+    Position::new(position.line, character)
+}
+
+// Convert position in the client encoding to the position with byte column
+pub fn from_client(text: &str, position: Position, encoding: Encoding) -> Position {
+    let Some(line) = line(text, position.line) else {
+        return position;
+    };
+    if encoding == Encoding::Utf8 {
+        let character = u32::try_from(clamp(line, position.character as usize)).unwrap();
+        return Position::new(position.line, character);
+    }
+
+    let mut units = 0;
+    let column = line
+        .char_indices()
+        .find(|(_, c)| {
+            let found = units >= position.character as usize;
+            units += encoding.len(c.encode_utf8(&mut [0; 4]));
+            found
+        })
+        .map_or(line.len(), |(i, _)| i);
+    Position::new(position.line, u32::try_from(column).unwrap())
+}
+
+pub fn range_to_client(text: &str, range: Range, encoding: Encoding) -> Range {
+    Range::new(
+        to_client(text, range.start, encoding),
+        to_client(text, range.end, encoding),
+    )
+}
+
+pub fn range_from_client(text: &str, range: Range, encoding: Encoding) -> Range {
+    Range::new(
+        from_client(text, range.start, encoding),
+        from_client(text, range.end, encoding),
+    )
+}
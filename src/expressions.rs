@@ -0,0 +1,225 @@
+use crate::macros::{expand_checked, is_identifier, Macro};
+use crate::utils::{parse, parse_integer};
+use tree_sitter::Node;
+
+/*
+ * Integer expressions are evaluated the way dtc does it: all arithmetic is done on unsigned
+ * 64-bit values with wrap around, comparisons and logical operators yield 0 or 1. Values are
+ * truncated to the width of the cell only when they are stored.
+ *
+ * Macros are expanded first, the same way as for expansion preview, and the result is
+ * evaluated. A macro that shows up in its own expansion is reported as recursive instead of
+ * being left unexpanded.
+ */
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    DivisionByZero,
+    // Chain of macros that leads back to the first one
+    Recursive(Vec<String>),
+    // Macro that has no value or doesn't evaluate to a number
+    Unresolved(String),
+    // Anything that is not a constant integer expression
+    Unsupported,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DivisionByZero => write!(f, "Division by zero"),
+            Error::Recursive(x) => write!(f, "Recursive macro: {}", x.join(" → ")),
+            Error::Unresolved(x) => write!(f, "Unresolved macro: {x}"),
+            Error::Unsupported => write!(f, "Not a constant expression"),
+        }
+    }
+}
+
+struct Evaluator<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<Macro>,
+    // Conditions of #if, where unknown identifiers are 0 and defined() is allowed
+    preprocessor: bool,
+}
+
+impl Evaluator<'_> {
+    fn text(&mut self, text: &str) -> Result<u64, Error> {
+        let text = expand_checked(text, self.lookup, false).map_err(Error::Recursive)?;
+        let text = text.trim();
+        if let Some(x) = parse_integer(text) {
+            return Ok(x);
+        }
+        if is_identifier(text) {
+            return self.identifier(text);
+        }
+
+        // Let the parser deal with precedence by wrapping expression into a property
+        let source = format!("/ {{ x = <({text})>; }};");
+        let tree = parse(&source);
+        if tree.root_node().has_error() {
+            return Err(Error::Unsupported);
+        }
+        let cells = tree
+            .root_node()
+            .named_child(0)
+            .and_then(|x| x.named_child(1))
+            .and_then(|x| x.child_by_field_name("value"))
+            .ok_or(Error::Unsupported)?;
+        let expression = cells.named_child(0).ok_or(Error::Unsupported)?;
+        self.node(&expression, &source)
+    }
+
+    // Macros are already expanded, those that are left are function-like macros without
+    // arguments or with wrong number of them
+    fn identifier(&mut self, name: &str) -> Result<u64, Error> {
+        match (self.lookup)(name) {
+            Some(_) => Err(Error::Unsupported),
+            None if self.preprocessor => Ok(0),
+            None => Err(Error::Unresolved(name.to_string())),
+        }
+    }
+
+    fn call(&mut self, node: &Node, text: &str) -> Result<u64, Error> {
+        let function = node
+            .child_by_field_name("function")
+            .ok_or(Error::Unsupported)?;
+        let name = function.utf8_text(text.as_bytes()).unwrap();
+        match (self.lookup)(name) {
+            Some(_) => Err(Error::Unsupported),
+            None => Err(Error::Unresolved(name.to_string())),
+        }
+    }
+
+    // Parentheses around operand are anonymous tokens under the same field
+    fn child(&mut self, node: &Node, field: &str, text: &str) -> Result<u64, Error> {
+        let mut cursor = node.walk();
+        let child = node
+            .children_by_field_name(field, &mut cursor)
+            .find(Node::is_named)
+            .ok_or(Error::Unsupported)?;
+        self.node(&child, text)
+    }
+
+    fn node(&mut self, node: &Node, text: &str) -> Result<u64, Error> {
+        let source = node.utf8_text(text.as_bytes()).unwrap();
+        match node.kind() {
+            "integer_literal" => parse_integer(source).ok_or(Error::Unsupported),
+            "identifier" => self.identifier(source),
+            "call_expression" => self.call(node, text),
+            "preproc_defined" if self.preprocessor => {
+                let name = node.named_child(0).ok_or(Error::Unsupported)?;
+                let name = name.utf8_text(text.as_bytes()).unwrap();
+                Ok(u64::from((self.lookup)(name).is_some()))
+            }
+            "parenthesized_expression" => {
+                let inner = node.named_child(0).ok_or(Error::Unsupported)?;
+                self.node(&inner, text)
+            }
+            "unary_expression" => {
+                let x = self.child(node, "argument", text)?;
+                let operator = node.child_by_field_name("operator").map(|x| x.kind());
+                match operator {
+                    Some("-") => Ok(x.wrapping_neg()),
+                    Some("+") => Ok(x),
+                    Some("~") => Ok(!x),
+                    Some("!") => Ok(u64::from(x == 0)),
+                    _ => Err(Error::Unsupported),
+                }
+            }
+            "conditional_expression" => {
+                if self.child(node, "condition", text)? != 0 {
+                    self.child(node, "consequence", text)
+                } else {
+                    self.child(node, "alternative", text)
+                }
+            }
+            "binary_expression" => self.binary(node, text),
+            _ => Err(Error::Unsupported),
+        }
+    }
+
+    fn binary(&mut self, node: &Node, text: &str) -> Result<u64, Error> {
+        let operator = node
+            .child_by_field_name("operator")
+            .ok_or(Error::Unsupported)?
+            .kind();
+        let a = self.child(node, "left", text)?;
+
+        // Right side is not evaluated if result is already known
+        match (operator, a) {
+            ("&&", 0) => return Ok(0),
+            ("||", x) if x != 0 => return Ok(1),
+            _ => (),
+        }
+
+        let b = self.child(node, "right", text)?;
+        let shift = |x: u64| u32::try_from(x).ok().filter(|x| *x < 64);
+        let res = match operator {
+            "+" => a.wrapping_add(b),
+            "-" => a.wrapping_sub(b),
+            "*" => a.wrapping_mul(b),
+            "/" => a.checked_div(b).ok_or(Error::DivisionByZero)?,
+            "%" => a.checked_rem(b).ok_or(Error::DivisionByZero)?,
+            "<<" => shift(b).map_or(0, |x| a << x),
+            ">>" => shift(b).map_or(0, |x| a >> x),
+            "&" => a & b,
+            "|" => a | b,
+            "^" => a ^ b,
+            "<" => u64::from(a < b),
+            ">" => u64::from(a > b),
+            "<=" => u64::from(a <= b),
+            ">=" => u64::from(a >= b),
+            "==" => u64::from(a == b),
+            "!=" => u64::from(a != b),
+            "&&" | "||" => u64::from(b != 0),
+            _ => return Err(Error::Unsupported),
+        };
+        Ok(res)
+    }
+}
+
+// Evaluate integer expression, macros are expanded with the lookup function
+pub fn evaluate(text: &str, lookup: &dyn Fn(&str) -> Option<Macro>) -> Result<u64, Error> {
+    let mut evaluator = Evaluator {
+        lookup,
+        preprocessor: false,
+    };
+    evaluator.text(text)
+}
+
+// Evaluate condition of #if or #elif
+pub fn condition(
+    node: &Node,
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<Macro>,
+) -> Result<bool, Error> {
+    let mut evaluator = Evaluator {
+        lookup,
+        preprocessor: true,
+    };
+    let source = node.utf8_text(text.as_bytes()).unwrap();
+    let expanded = expand_checked(source, lookup, true).map_err(Error::Recursive)?;
+    if expanded == source {
+        return evaluator.node(node, text).map(|x| x != 0);
+    }
+
+    // Expanded condition is parsed again, operands of defined are kept as is
+    let source = format!("#if {expanded}\n#endif\n");
+    let tree = parse(&source);
+    if tree.root_node().has_error() {
+        return Err(Error::Unsupported);
+    }
+    let condition = tree
+        .root_node()
+        .named_child(0)
+        .and_then(|x| x.child_by_field_name("condition"))
+        .ok_or(Error::Unsupported)?;
+    evaluator.node(&condition, &source).map(|x| x != 0)
+}
+
+// Value fits into the cell, negative values are allowed as long as they are sign-extended
+pub fn fits(value: u64, bits: u32) -> bool {
+    if bits >= 64 {
+        return true;
+    }
+    let mask = (1u64 << bits) - 1;
+    value <= mask || (value | (mask >> 1)) == u64::MAX
+}
@@ -0,0 +1,419 @@
+use crate::device_tree::{Cell, CellKind, DeviceTree, Property, Reference, Value};
+use crate::utils::extension_one_of;
+use std::fmt::Write;
+use tower_lsp::lsp_types::{Location, Range, Url};
+
+/*
+ * Flattened device tree, as described in Devicetree Specification: header is followed by
+ * memory reservation block, structure block with nodes and properties, and strings block with
+ * property names. All numbers are big-endian. Types of property values are not stored, they
+ * are guessed the same way dtc does it when decompiling.
+ */
+
+const MAGIC: u32 = 0xd00d_feed;
+// Oldest version that has all the fields of the header used here
+const MIN_VERSION: u32 = 16;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    Truncated,
+    BadMagic(u32),
+    UnsupportedVersion(u32),
+    BadToken(u32),
+    BadString,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Truncated => write!(f, "Unexpected end of blob"),
+            Error::BadMagic(x) => write!(f, "Bad magic {x:#x}, not a device tree blob"),
+            Error::UnsupportedVersion(x) => write!(f, "Unsupported version {x}"),
+            Error::BadToken(x) => write!(f, "Bad token {x:#x} in structure block"),
+            Error::BadString => write!(f, "Malformed string in structure block"),
+        }
+    }
+}
+
+pub struct Blob {
+    pub tree: DeviceTree,
+    // Memory reservation entries, address and size
+    pub reserved: Vec<(u64, u64)>,
+    // Compiled overlay, i.e. .dtbo file
+    pub plugin: bool,
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl Reader<'_> {
+    fn bytes(&mut self, len: usize) -> Result<&[u8], Error> {
+        let end = self.offset.checked_add(len).ok_or(Error::Truncated)?;
+        let res = self.data.get(self.offset..end).ok_or(Error::Truncated)?;
+        self.offset = end;
+        Ok(res)
+    }
+
+    fn u32(&mut self) -> Result<u32, Error> {
+        let x = self.bytes(4)?;
+        Ok(u32::from_be_bytes([x[0], x[1], x[2], x[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, Error> {
+        Ok((u64::from(self.u32()?) << 32) | u64::from(self.u32()?))
+    }
+
+    // Structure block items are aligned to 4 bytes
+    fn align(&mut self) {
+        self.offset = self.offset.next_multiple_of(4);
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        let rest = self.data.get(self.offset..).ok_or(Error::Truncated)?;
+        let len = rest.iter().position(|x| *x == 0).ok_or(Error::Truncated)?;
+        let res = std::str::from_utf8(&rest[..len]).map_err(|_| Error::BadString)?;
+        self.offset += len + 1;
+        Ok(res.to_string())
+    }
+}
+
+fn offset(x: u32) -> usize {
+    usize::try_from(x).unwrap()
+}
+
+// Strings separated by NUL, as long as all of them are non-empty and printable. Strings are
+// escaped the way they are written in the source.
+fn strings(value: &[u8]) -> Option<Vec<String>> {
+    let value = value.strip_suffix(&[0])?;
+    value
+        .split(|x| *x == 0)
+        .map(|x| {
+            let printable =
+                !x.is_empty() && x.iter().all(|x| (0x20..0x7f).contains(x) || *x == b'\t');
+            printable.then(|| escape(&String::from_utf8_lossy(x)))
+        })
+        .collect()
+}
+
+pub fn values(value: &[u8]) -> Vec<Value> {
+    if value.is_empty() {
+        return Vec::new();
+    }
+    if let Some(x) = strings(value) {
+        return x.into_iter().map(Value::String).collect();
+    }
+    if !value.len().is_multiple_of(4) {
+        return vec![Value::Bytes(value.to_vec())];
+    }
+
+    let cells = value
+        .chunks_exact(4)
+        .map(|x| Cell {
+            kind: CellKind::Expression,
+            text: format!("{:#x}", u32::from_be_bytes([x[0], x[1], x[2], x[3]])),
+            range: Range::default(),
+        })
+        .collect();
+    vec![Value::Cells(32, cells)]
+}
+
+fn structure(reader: &mut Reader, names: &Reader, uri: &Url) -> Result<DeviceTree, Error> {
+    let mut tree = DeviceTree::new();
+    let mut stack: Vec<usize> = Vec::new();
+    loop {
+        match reader.u32()? {
+            FDT_BEGIN_NODE => {
+                let name = reader.string()?;
+                reader.align();
+                // Root node is created beforehand
+                let idx = match stack.last() {
+                    None => DeviceTree::ROOT,
+                    Some(parent) => tree.add_child(*parent, &name),
+                };
+                let location = Location::new(uri.clone(), Range::default());
+                tree.node_mut(idx).locations.push(location);
+                stack.push(idx);
+            }
+            FDT_END_NODE => {
+                stack.pop().ok_or(Error::BadToken(FDT_END_NODE))?;
+            }
+            FDT_PROP => {
+                let len = offset(reader.u32()?);
+                let name_offset = offset(reader.u32()?);
+                let value = reader.bytes(len)?.to_vec();
+                reader.align();
+
+                let mut name_reader = Reader {
+                    data: names.data,
+                    offset: names.offset + name_offset,
+                };
+                let idx = *stack.last().ok_or(Error::BadToken(FDT_PROP))?;
+                tree.node_mut(idx).properties.push(Property {
+                    name: name_reader.string()?,
+                    values: values(&value),
+                    uri: uri.clone(),
+                    range: Range::default(),
+                });
+            }
+            FDT_NOP => (),
+            FDT_END if stack.is_empty() => return Ok(tree),
+            x => return Err(Error::BadToken(x)),
+        }
+    }
+}
+
+// Labels of the blobs compiled with symbols (-@) are stored as paths in /__symbols__ node
+fn symbols(tree: &DeviceTree) -> Vec<(String, usize)> {
+    let Some(symbols) = tree.find_path("/__symbols__") else {
+        return Vec::new();
+    };
+    tree.node(symbols)
+        .properties
+        .iter()
+        .filter_map(|x| match x.strings().as_slice() {
+            [path] => Some((x.name.clone(), tree.find_path(path)?)),
+            _ => None,
+        })
+        .collect()
+}
+
+/*
+ * Phandles of labels outside of the overlay are not known when it is compiled, so cells that
+ * refer to them are stored as placeholders. /__fixups__ lists `path:property:offset` of every
+ * such cell, they are turned back into references and generated nodes are dropped.
+ */
+fn fixups(tree: &mut DeviceTree) {
+    let Some(fixups) = tree.find_path("/__fixups__") else {
+        return;
+    };
+    let entries: Vec<(String, Vec<String>)> = tree
+        .node(fixups)
+        .properties
+        .iter()
+        .map(|x| {
+            (
+                x.name.clone(),
+                x.strings().into_iter().map(String::from).collect(),
+            )
+        })
+        .collect();
+
+    for (label, locations) in entries {
+        for location in locations {
+            let mut parts = location.rsplitn(3, ':');
+            let (Some(offset), Some(property), Some(path)) =
+                (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            let (Ok(offset), Some(idx)) = (offset.parse::<usize>(), tree.find_path(path)) else {
+                continue;
+            };
+            let properties = &mut tree.node_mut(idx).properties;
+            let Some(property) = properties.iter_mut().find(|x| x.name == property) else {
+                continue;
+            };
+            if let [Value::Cells(_, cells)] = property.values.as_mut_slice() {
+                if let Some(cell) = cells.get_mut(offset / 4) {
+                    cell.kind = CellKind::Reference(Reference::Label(label.clone()));
+                    cell.text = format!("&{label}");
+                }
+            }
+        }
+    }
+
+    for path in ["/__fixups__", "/__local_fixups__"] {
+        if let Some(idx) = tree.find_path(path) {
+            tree.delete_node(idx);
+        }
+    }
+}
+
+pub fn parse(data: &[u8], uri: &Url) -> Result<Blob, Error> {
+    let mut header = Reader { data, offset: 0 };
+    let magic = header.u32()?;
+    if magic != MAGIC {
+        return Err(Error::BadMagic(magic));
+    }
+    let _total_size = header.u32()?;
+    let off_dt_struct = offset(header.u32()?);
+    let off_dt_strings = offset(header.u32()?);
+    let off_mem_rsvmap = offset(header.u32()?);
+    let version = header.u32()?;
+    if version < MIN_VERSION {
+        return Err(Error::UnsupportedVersion(version));
+    }
+
+    let mut reader = Reader {
+        data,
+        offset: off_mem_rsvmap,
+    };
+    let mut reserved = Vec::new();
+    loop {
+        match (reader.u64()?, reader.u64()?) {
+            (0, 0) => break,
+            x => reserved.push(x),
+        }
+    }
+
+    let mut reader = Reader {
+        data,
+        offset: off_dt_struct,
+    };
+    let names = Reader {
+        data,
+        offset: off_dt_strings,
+    };
+    let mut tree = structure(&mut reader, &names, uri)?;
+    for (label, idx) in symbols(&tree) {
+        tree.add_label(&label, idx);
+    }
+
+    let plugin = extension_one_of(uri, &["dtbo"]);
+    if plugin {
+        fixups(&mut tree);
+    }
+    Ok(Blob {
+        tree,
+        reserved,
+        plugin,
+    })
+}
+
+// Characters of string literals and escape sequences they are written with
+const ESCAPES: [(u8, u8); 9] = [
+    (b'\\', b'\\'),
+    (b'"', b'"'),
+    (0x07, b'a'),
+    (0x08, b'b'),
+    (b'\t', b't'),
+    (b'\n', b'n'),
+    (0x0b, b'v'),
+    (0x0c, b'f'),
+    (b'\r', b'r'),
+];
+
+fn escape(text: &str) -> String {
+    let mut res = String::new();
+    for c in text.chars() {
+        match ESCAPES.iter().find(|x| char::from(x.0) == c) {
+            Some(x) => {
+                res.push('\\');
+                res.push(char::from(x.1));
+            }
+            None => res.push(c),
+        }
+    }
+    res
+}
+
+// Bytes of a string literal as dtc stores them, with octal and hex escapes resolved too
+pub fn unescape(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    let mut res = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let (c, next) = (bytes[i], bytes.get(i + 1).copied());
+        i += 1;
+        let Some(next) = next.filter(|_| c == b'\\') else {
+            res.push(c);
+            continue;
+        };
+        i += 1;
+
+        let (radix, max) = match next {
+            b'x' => (16, 2),
+            b'0'..=b'7' => {
+                i -= 1;
+                (8, 3)
+            }
+            x => {
+                let escape = ESCAPES.iter().find(|e| e.1 == x);
+                res.push(escape.map_or(x, |e| e.0));
+                continue;
+            }
+        };
+        let digits = bytes[i..]
+            .iter()
+            .take(max)
+            .take_while(|x| char::from(**x).is_digit(radix))
+            .count();
+        let value = std::str::from_utf8(&bytes[i..i + digits])
+            .ok()
+            .and_then(|x| u32::from_str_radix(x, radix).ok());
+        match value {
+            Some(x) => res.push(x.to_le_bytes()[0]),
+            None => res.push(next),
+        }
+        i += digits;
+    }
+    res
+}
+
+pub fn render_value(value: &Value) -> String {
+    match value {
+        Value::String(x) => format!("\"{x}\""),
+        Value::Cells(_, cells) => {
+            let cells: Vec<_> = cells.iter().map(|x| x.text.as_str()).collect();
+            format!("<{}>", cells.join(" "))
+        }
+        Value::Bytes(x) => {
+            let bytes: Vec<_> = x.iter().map(|x| format!("{x:02x}")).collect();
+            format!("[{}]", bytes.join(" "))
+        }
+        Value::Path(x) => x.to_string(),
+        Value::Other => String::new(),
+    }
+}
+
+fn render_node(tree: &DeviceTree, idx: usize, depth: usize, res: &mut String) {
+    let node = tree.node(idx);
+    let indent = "\t".repeat(depth);
+    let labels: String = node.labels.iter().map(|x| x.clone() + ": ").collect();
+    let name = if idx == DeviceTree::ROOT {
+        "/"
+    } else {
+        &node.name
+    };
+    writeln!(res, "{indent}{labels}{name} {{").unwrap();
+
+    for property in &node.properties {
+        let values: Vec<_> = property.values.iter().map(render_value).collect();
+        if values.is_empty() {
+            writeln!(res, "{indent}\t{};", property.name).unwrap();
+        } else {
+            writeln!(res, "{indent}\t{} = {};", property.name, values.join(", ")).unwrap();
+        }
+    }
+    for child in &node.children {
+        res.push('\n');
+        render_node(tree, *child, depth + 1, res);
+    }
+
+    writeln!(res, "{indent}}};").unwrap();
+}
+
+// Source of the blob as dtc would decompile it
+pub fn render(blob: &Blob) -> String {
+    let mut res = String::from("/dts-v1/;\n");
+    if blob.plugin {
+        res.push_str("/plugin/;\n");
+    }
+    res.push('\n');
+    for (address, size) in &blob.reserved {
+        writeln!(res, "/memreserve/ {address:#x} {size:#x};").unwrap();
+    }
+    if !blob.reserved.is_empty() {
+        res.push('\n');
+    }
+    render_node(&blob.tree, DeviceTree::ROOT, 0, &mut res);
+    res
+}
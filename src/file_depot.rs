@@ -1,17 +1,24 @@
+use crate::encoding;
+use crate::encoding::Encoding;
+use crate::utils::is_overlay;
 use crate::utils::url_exists;
 use crate::{error, log_message, utils::is_header};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::Arc;
 use std::sync::Mutex;
-use tower_lsp::lsp_types::{MessageType, TextEdit, Url};
+use tower_lsp::lsp_types::{Location, MessageType, Position, Range, Url};
 
 #[cfg(test)]
 use crate::info;
+#[cfg(test)]
+use tower_lsp::lsp_types::TextEdit;
 
 #[derive(Default, Clone)]
 struct FileEntry {
     text: Option<String>,
+    // Version of the document as reported by client, None if file is not open
+    version: Option<i32>,
     includes: Vec<Url>,
     included_by: Vec<Url>,
 }
@@ -19,6 +26,8 @@ struct FileEntry {
 #[derive(Clone)]
 struct Data {
     root_dir: Option<Url>, // TODO: Maybe some type that allows only one assignment?
+    overlay_base: Option<Url>,
+    encoding: Encoding,
     entries: HashMap<Url, FileEntry>,
 }
 
@@ -29,26 +38,8 @@ pub enum InsertResult {
     Modified,
 }
 
-struct MyTextEdit<'a>(&'a TextEdit);
-impl MyTextEdit<'_> {
-    fn new_text(&self) -> &str {
-        &self.0.new_text
-    }
-
-    fn lines_range(&self) -> (usize, usize) {
-        (
-            self.0.range.start.line as usize,
-            self.0.range.end.line as usize,
-        )
-    }
-
-    fn chars_range(&self) -> (usize, usize) {
-        (
-            self.0.range.start.character as usize,
-            self.0.range.end.character as usize,
-        )
-    }
-}
+// TODO: make it configurable
+pub const INCLUDE_PREFIXES: [&str; 3] = ["include/", "arch/", "scripts/dtc/include-prefixes/"];
 
 fn build_path(root: &Url, includes_dir: &str, rel_path: &str) -> Option<Url> {
     let Ok(dst) = root.join(includes_dir) else {
@@ -72,40 +63,22 @@ impl Data {
     fn new() -> Data {
         Data {
             root_dir: None,
+            overlay_base: None,
+            encoding: Encoding::default(),
             entries: HashMap::new(),
         }
     }
 
+    // Edits are applied one by one, positions have byte columns
+    #[cfg(test)]
     fn apply_edits(&mut self, uri: &Url, edits: &Vec<TextEdit>) -> Result<(), String> {
+        let Some(text) = self.entries.get_mut(uri).and_then(|x| x.text.as_mut()) else {
+            return Err("Failed to apply edits".to_string());
+        };
         for edit in edits {
-            let edit = MyTextEdit(edit);
-            let Some(e) = self.entries.get_mut(uri) else {
-                return Err("Failed to apply edits".to_string());
-            };
-            let mut new_text = String::new();
-            if let Some(text) = &e.text {
-                let (start_line, end_line) = edit.lines_range();
-                let (start_char, end_char) = edit.chars_range();
-                let multiline = start_line != end_line;
-
-                for (n, line) in text.split_inclusive('\n').enumerate() {
-                    let mut line = line.to_string();
-
-                    #[allow(clippy::if_same_then_else)]
-                    if n == start_line && !multiline {
-                        line.replace_range(start_char..end_char, edit.new_text());
-                    } else if n == start_line && multiline {
-                        line.replace_range(start_char.., "");
-                    } else if (start_line + 1..end_line).contains(&n) {
-                        continue;
-                    } else if n == end_line {
-                        line.replace_range(..end_char, edit.new_text());
-                    }
-
-                    new_text.push_str(&line);
-                }
-                e.text = Some(new_text);
-            }
+            let start = encoding::offset(text, edit.range.start);
+            let end = encoding::offset(text, edit.range.end).max(start);
+            text.replace_range(start..end, &edit.new_text);
         }
         Ok(())
     }
@@ -132,8 +105,7 @@ impl Data {
             return None;
         };
 
-        // TODO: make it configurable
-        for prefix in ["include/", "arch/", "scripts/dtc/include-prefixes/"] {
+        for prefix in INCLUDE_PREFIXES {
             let dst = build_path(root, prefix, rel_path);
             if dst.is_some() {
                 return dst;
@@ -142,6 +114,37 @@ impl Data {
         None
     }
 
+    fn resolve_include(&self, uri: &Url, path: &str) -> Option<Url> {
+        let new_url = uri.join(path).ok()?;
+
+        if url_exists(&new_url) {
+            Some(new_url)
+        } else {
+            self.get_real_path(path)
+        }
+    }
+
+    // Argument of include directive in the file that resolves to the header, include prefixes
+    // are preferred over paths relative to the file
+    fn include_directive_path(&self, uri: &Url, header: &Url) -> Option<String> {
+        let resolves = |path: &str| self.resolve_include(uri, path).as_ref() == Some(header);
+
+        if let Some(root) = &self.root_dir {
+            for prefix in INCLUDE_PREFIXES {
+                let Some(path) = root.join(prefix).ok().and_then(|x| x.make_relative(header))
+                else {
+                    continue;
+                };
+                if !path.starts_with("../") && resolves(&path) {
+                    return Some(format!("<{path}>"));
+                }
+            }
+        }
+
+        let path = uri.make_relative(header)?;
+        resolves(&path).then(|| format!("\"{path}\""))
+    }
+
     fn add_include(&mut self, uri: &Url, include_uri: &Url) {
         let e = self.entries.entry(uri.clone()).or_default();
         e.includes.push(include_uri.clone());
@@ -205,6 +208,36 @@ impl Data {
         res.iter().cloned().collect()
     }
 
+    // Top-level files (i.e. boards) that include uri directly or indirectly
+    fn get_roots(&self, uri: &Url) -> Vec<Url> {
+        let mut visited = HashSet::from([uri.clone()]);
+        let mut to_visit = vec![uri.clone()];
+        let mut res = Vec::new();
+
+        while let Some(uri) = to_visit.pop() {
+            // Overlays are applied on top of base tree, but they are not part of it
+            let included_by: Vec<&Url> = self
+                .entries
+                .get(&uri)
+                .map(|x| x.included_by.as_slice())
+                .unwrap_or_default()
+                .iter()
+                .filter(|x| !is_overlay(x))
+                .collect();
+
+            if included_by.is_empty() {
+                res.push(uri.clone());
+            }
+
+            for f in included_by {
+                if visited.insert(f.clone()) {
+                    to_visit.push(f.clone());
+                }
+            }
+        }
+        res
+    }
+
     #[cfg(test)]
     fn dump(&self) {
         info!("===FILES===");
@@ -228,6 +261,34 @@ impl Data {
         self.entries.get(uri).and_then(|x| x.text.clone())
     }
 
+    fn text(&self, uri: &Url) -> Option<&str> {
+        self.entries.get(uri).and_then(|x| x.text.as_deref())
+    }
+
+    fn range_to_client(&self, uri: &Url, range: Range) -> Range {
+        match self.text(uri) {
+            Some(text) => encoding::range_to_client(text, range, self.encoding),
+            None => range,
+        }
+    }
+
+    fn range_from_client(&self, uri: &Url, range: Range) -> Range {
+        match self.text(uri) {
+            Some(text) => encoding::range_from_client(text, range, self.encoding),
+            None => range,
+        }
+    }
+
+    fn set_version(&mut self, uri: &Url, version: i32) {
+        if let Some(e) = self.entries.get_mut(uri) {
+            e.version = Some(version);
+        }
+    }
+
+    fn get_version(&self, uri: &Url) -> Option<i32> {
+        self.entries.get(uri).and_then(|x| x.version)
+    }
+
     fn set_root_dir(&mut self, uri: &Url) {
         /* root_dir comes from LSP client and it's better to
          * verify that there is a trailing slash */
@@ -243,6 +304,18 @@ impl Data {
         self.root_dir.clone()
     }
 
+    fn set_overlay_base(&mut self, path: &str) {
+        let Some(root) = &self.root_dir else {
+            error!("Root dir is not set");
+            return;
+        };
+
+        match root.join(path) {
+            Ok(x) => self.overlay_base = Some(x),
+            Err(e) => error!("Invalid overlay base {path}: {e}"),
+        }
+    }
+
     #[cfg(test)]
     fn size(&self) -> usize {
         self.entries.keys().count()
@@ -287,6 +360,43 @@ impl FileDepot {
         self.data.lock().unwrap().get_text(uri)
     }
 
+    pub fn set_encoding(&self, encoding: Encoding) {
+        self.data.lock().unwrap().encoding = encoding;
+    }
+
+    // Convert range with byte columns to client position encoding
+    pub fn range_to_client(&self, uri: &Url, range: Range) -> Range {
+        self.data.lock().unwrap().range_to_client(uri, range)
+    }
+
+    // Convert range in client position encoding to range with byte columns
+    pub fn range_from_client(&self, uri: &Url, range: Range) -> Range {
+        self.data.lock().unwrap().range_from_client(uri, range)
+    }
+
+    pub fn position_to_client(&self, uri: &Url, position: Position) -> Position {
+        self.range_to_client(uri, Range::new(position, position))
+            .start
+    }
+
+    pub fn position_from_client(&self, uri: &Url, position: Position) -> Position {
+        self.range_from_client(uri, Range::new(position, position))
+            .start
+    }
+
+    pub fn location_to_client(&self, location: Location) -> Location {
+        let range = self.range_to_client(&location.uri, location.range);
+        Location::new(location.uri, range)
+    }
+
+    pub fn set_version(&self, uri: &Url, version: i32) {
+        self.data.lock().unwrap().set_version(uri, version);
+    }
+
+    pub fn get_version(&self, uri: &Url) -> Option<i32> {
+        self.data.lock().unwrap().get_version(uri)
+    }
+
     pub fn exist(&self, uri: &Url) -> bool {
         self.data.lock().unwrap().exist(uri)
     }
@@ -299,6 +409,11 @@ impl FileDepot {
         self.data.lock().unwrap().get_component(uri)
     }
 
+    pub fn get_roots(&self, uri: &Url) -> Vec<Url> {
+        self.data.lock().unwrap().get_roots(uri)
+    }
+
+    #[cfg(test)]
     pub fn apply_edits(&self, uri: &Url, edits: &Vec<TextEdit>) {
         if let Err(e) = {
             let mut data = self.data.lock().unwrap();
@@ -308,14 +423,29 @@ impl FileDepot {
         }
     }
 
-    pub fn get_real_path(&self, uri: &str) -> Option<Url> {
-        self.data.lock().unwrap().get_real_path(uri)
+    pub fn resolve_include(&self, uri: &Url, path: &str) -> Option<Url> {
+        self.data.lock().unwrap().resolve_include(uri, path)
+    }
+
+    pub fn include_directive_path(&self, uri: &Url, header: &Url) -> Option<String> {
+        self.data
+            .lock()
+            .unwrap()
+            .include_directive_path(uri, header)
     }
 
     pub fn set_root_dir(&self, uri: &Url) {
         self.data.lock().unwrap().set_root_dir(uri);
     }
 
+    pub fn set_overlay_base(&self, path: &str) {
+        self.data.lock().unwrap().set_overlay_base(path);
+    }
+
+    pub fn get_overlay_base(&self) -> Option<Url> {
+        self.data.lock().unwrap().overlay_base.clone()
+    }
+
     #[cfg(any(test, feature = "walkdir"))]
     pub fn get_root_dir(&self) -> Option<Url> {
         self.data.lock().unwrap().get_root_dir()
@@ -0,0 +1,113 @@
+use crate::diagnostics::walk;
+use tower_lsp::lsp_types::{FoldingRange, FoldingRangeKind};
+use tree_sitter::{Node, Tree};
+
+/*
+ * Folding is done by whole lines, so ranges don't depend on position encoding. Closing lines
+ * of nodes and preprocessor conditionals stay visible, like with indentation based folding.
+ */
+
+fn row(x: usize) -> u32 {
+    u32::try_from(x).unwrap()
+}
+
+fn push(res: &mut Vec<FoldingRange>, start: usize, end: usize, kind: Option<FoldingRangeKind>) {
+    if end > start {
+        res.push(FoldingRange {
+            start_line: row(start),
+            end_line: row(end),
+            kind,
+            ..Default::default()
+        });
+    }
+}
+
+// Last line of the node, preprocessor directives include trailing newline
+fn last_row(node: &Node) -> usize {
+    let end = node.end_position();
+    if end.column == 0 && end.row > node.start_position().row {
+        end.row - 1
+    } else {
+        end.row
+    }
+}
+
+fn token_row(node: &Node, kind: &str) -> Option<usize> {
+    let mut cursor = node.walk();
+    let res = node
+        .children(&mut cursor)
+        .find(|x| x.kind() == kind)
+        .map(|x| x.start_position().row);
+    res
+}
+
+// Every branch of conditional folds up to the next #elif, #else or #endif
+fn conditional(node: &Node, endif: usize, res: &mut Vec<FoldingRange>) {
+    let alternative = node.child_by_field_name("alternative");
+    let end = alternative.map_or(endif, |x| x.start_position().row);
+    push(
+        res,
+        node.start_position().row,
+        end.saturating_sub(1),
+        Some(FoldingRangeKind::Region),
+    );
+
+    if let Some(x) = alternative {
+        conditional(&x, endif, res);
+    }
+}
+
+// Runs of consecutive includes
+fn includes(tree: &Tree, res: &mut Vec<FoldingRange>) {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let mut run: Option<(usize, usize)> = None;
+
+    for x in root.named_children(&mut cursor) {
+        let is_include = matches!(x.kind(), "preproc_include" | "dtsi_include");
+        run = match run {
+            Some((start, end)) if is_include && x.start_position().row == end + 1 => {
+                Some((start, last_row(&x)))
+            }
+            _ => {
+                if let Some((start, end)) = run {
+                    push(res, start, end, Some(FoldingRangeKind::Imports));
+                }
+                is_include.then(|| (x.start_position().row, last_row(&x)))
+            }
+        };
+    }
+
+    if let Some((start, end)) = run {
+        push(res, start, end, Some(FoldingRangeKind::Imports));
+    }
+}
+
+pub fn folding_ranges(tree: &Tree, text: &str) -> Vec<FoldingRange> {
+    let mut res = Vec::new();
+    includes(tree, &mut res);
+
+    walk(tree.root_node(), &mut |node| match node.kind() {
+        "node" => {
+            let start = token_row(&node, "{").unwrap_or(node.start_position().row);
+            if let Some(end) = token_row(&node, "}") {
+                push(&mut res, start, end.saturating_sub(1), None);
+            }
+        }
+        "property" => push(&mut res, node.start_position().row, last_row(&node), None),
+        "comment" if text[node.byte_range()].starts_with("/*") => push(
+            &mut res,
+            node.start_position().row,
+            last_row(&node),
+            Some(FoldingRangeKind::Comment),
+        ),
+        "preproc_if" | "preproc_ifdef" => {
+            let endif = token_row(&node, "#endif").unwrap_or(last_row(&node) + 1);
+            conditional(&node, endif, &mut res);
+        }
+        _ => (),
+    });
+
+    res.sort_by_key(|x| (x.start_line, x.end_line));
+    res
+}
@@ -0,0 +1,535 @@
+use crate::encoding::convert_range;
+use tower_lsp::lsp_types::{Position, Range};
+use tree_sitter::{Node, Tree};
+
+/*
+ * Formatter follows kernel DTS coding style (Documentation/devicetree/bindings/dts-coding-style.rst):
+ * tabs for indentation, properties before child nodes, nodes separated by a blank line and
+ * properties ordered as compatible, reg, ranges, standard, vendor specific and status.
+ * Comments and preprocessor lines are kept as they are, anything not understood by the
+ * formatter is copied without changes.
+ */
+
+// Properties go in this order, child nodes after all of them
+fn rank(item: &Node, text: &str) -> u8 {
+    if is_node(item) {
+        return 6;
+    }
+
+    let name = item
+        .child_by_field_name("name")
+        .and_then(|x| x.utf8_text(text.as_bytes()).ok())
+        .unwrap_or_default();
+    match name {
+        "compatible" => 0,
+        "reg" => 1,
+        "ranges" => 2,
+        "status" => 5,
+        x if x.contains(',') => 4,
+        _ => 3,
+    }
+}
+
+fn is_node(node: &Node) -> bool {
+    matches!(node.kind(), "node" | "omit_if_no_ref")
+}
+
+fn is_statement(node: &Node) -> bool {
+    is_node(node) || node.kind() == "property"
+}
+
+// Last row that has text of the node, preprocessor directives include trailing newline
+fn end_row(node: &Node) -> usize {
+    let end = node.end_position();
+    if end.column == 0 && end.row > node.start_position().row {
+        end.row - 1
+    } else {
+        end.row
+    }
+}
+
+fn has_comments(node: &Node) -> bool {
+    let mut cursor = node.walk();
+    let res = node
+        .children(&mut cursor)
+        .any(|x| x.kind() == "comment" || has_comments(&x));
+    res
+}
+
+// Cells of <...> as first and last token, parenthesized expressions are single cells
+fn cells<'t>(node: &Node<'t>) -> Vec<(Node<'t>, Node<'t>)> {
+    let mut res = Vec::new();
+    let mut cursor = node.walk();
+    let mut depth = 0;
+    let mut start = None;
+    for child in node.children(&mut cursor) {
+        match (child.kind(), depth) {
+            ("<" | ">", 0) => {}
+            ("(", _) => {
+                if depth == 0 {
+                    start = Some(child);
+                }
+                depth += 1;
+            }
+            (")", _) => {
+                depth -= 1;
+                if depth == 0 {
+                    res.push((start.take().unwrap(), child));
+                }
+            }
+            (_, 0) => res.push((child, child)),
+            _ => {}
+        }
+    }
+    res
+}
+
+fn indent(depth: usize) -> String {
+    "\t".repeat(depth)
+}
+
+// Multiline text is joined into a single line, whitespace inside of string and character
+// literals is kept as it is
+fn join_lines(text: &str) -> String {
+    if !text.contains('\n') {
+        return text.to_string();
+    }
+
+    let mut res = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    let mut space = false;
+    for c in text.trim().chars() {
+        match quote {
+            Some(q) => {
+                quote = (escaped || c != q).then_some(q);
+                escaped = !escaped && c == '\\';
+            }
+            None if c.is_whitespace() => {
+                space = true;
+                continue;
+            }
+            None => quote = matches!(c, '"' | '\'').then_some(c),
+        }
+        if std::mem::take(&mut space) {
+            res.push(' ');
+        }
+        res.push(c);
+    }
+    res
+}
+
+// Statement with comments attached to it
+struct Item<'t> {
+    leading: Vec<Node<'t>>,
+    node: Node<'t>,
+    trailing: Option<Node<'t>>,
+}
+
+impl Item<'_> {
+    fn start_row(&self) -> usize {
+        self.leading
+            .first()
+            .unwrap_or(&self.node)
+            .start_position()
+            .row
+    }
+
+    fn end_row(&self) -> usize {
+        end_row(self.trailing.as_ref().unwrap_or(&self.node))
+    }
+
+    fn range(&self) -> Range {
+        let start = Position::new(u32::try_from(self.start_row()).unwrap(), 0);
+        let end = convert_range(&self.trailing.unwrap_or(self.node).range()).end;
+        Range::new(start, end)
+    }
+}
+
+// Comments that don't belong to any statement are items on their own
+fn flush<'t>(res: &mut Vec<Item<'t>>, pending: &mut Vec<Node<'t>>) {
+    for x in pending.drain(..) {
+        res.push(Item {
+            leading: Vec::new(),
+            node: x,
+            trailing: None,
+        });
+    }
+}
+
+// Comments on the same line go after the statement, comments right above it go before
+fn items(children: Vec<Node>) -> Vec<Item> {
+    let mut res: Vec<Item> = Vec::new();
+    let mut pending: Vec<Node> = Vec::new();
+    for child in children {
+        let row = child.start_position().row;
+        if child.kind() == "comment" {
+            if let Some(last) = res.last_mut().filter(|x| {
+                pending.is_empty()
+                    && x.trailing.is_none()
+                    && x.node.kind() != "comment"
+                    && end_row(&x.node) == row
+            }) {
+                last.trailing = Some(child);
+                continue;
+            }
+        }
+
+        // Comments separated by blank line don't belong to the next statement
+        if pending.last().is_some_and(|x| end_row(x) + 1 < row) {
+            flush(&mut res, &mut pending);
+        }
+
+        if child.kind() == "comment" {
+            pending.push(child);
+        } else {
+            res.push(Item {
+                leading: std::mem::take(&mut pending),
+                node: child,
+                trailing: None,
+            });
+        }
+    }
+
+    flush(&mut res, &mut pending);
+    res
+}
+
+// Line of property value, cells are kept separately to be aligned with other lines
+#[derive(Default)]
+struct Line {
+    offset: usize,
+    head: String,
+    cells: Option<Vec<String>>,
+    tail: String,
+}
+
+impl Line {
+    fn width(&self) -> usize {
+        self.offset + self.head.chars().count()
+    }
+
+    fn flatten(&mut self) {
+        if let Some(cells) = self.cells.take() {
+            self.head.push_str(&cells.join(" "));
+            self.head.push_str(&std::mem::take(&mut self.tail));
+        }
+    }
+
+    fn push(&mut self, text: &str) {
+        match self.cells {
+            Some(_) => self.tail.push_str(text),
+            None => self.head.push_str(text),
+        }
+    }
+
+    fn render(&self, depth: usize, widths: &[usize]) -> String {
+        let cells = self.cells.as_ref().map(|cells| {
+            let padded: Vec<String> = cells
+                .iter()
+                .enumerate()
+                .map(|(i, x)| match widths.get(i) {
+                    Some(w) if i + 1 < cells.len() => format!("{x:w$}"),
+                    _ => x.clone(),
+                })
+                .collect();
+            padded.join(" ")
+        });
+        [
+            indent(depth),
+            " ".repeat(self.offset),
+            self.head.clone(),
+            cells.unwrap_or_default(),
+            self.tail.clone(),
+        ]
+        .concat()
+    }
+}
+
+struct Formatter<'a> {
+    text: &'a str,
+}
+
+impl<'a> Formatter<'a> {
+    fn text(&self, node: &Node) -> &'a str {
+        node.utf8_text(self.text.as_bytes()).unwrap()
+    }
+
+    fn inline(&self, node: &Node) -> String {
+        join_lines(self.text(node))
+    }
+
+    // Copy text without changes, only first line is reindented
+    fn verbatim(&self, node: &Node, depth: usize) -> Vec<String> {
+        let text = self.text(node).trim_end();
+        let mut lines: Vec<String> = text.split('\n').map(|x| x.trim_end().to_string()).collect();
+        // Preprocessor directives always start at the beginning of the line
+        if !node.kind().starts_with("preproc") {
+            lines[0] = indent(depth) + lines[0].trim_start();
+        }
+        lines
+    }
+
+    fn labels(&self, node: &Node) -> String {
+        let mut cursor = node.walk();
+        node.children_by_field_name("label", &mut cursor)
+            .map(|x| format!("{}: ", self.text(&x)))
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    fn property(&self, node: &Node, depth: usize) -> Vec<String> {
+        if has_comments(node) {
+            return self.verbatim(node, depth);
+        }
+
+        let name = node
+            .child_by_field_name("name")
+            .map(|x| self.text(&x))
+            .unwrap_or_default();
+        let mut cursor = node.walk();
+        let values: Vec<Node> = node
+            .children_by_field_name("value", &mut cursor)
+            .filter(Node::is_named)
+            .collect();
+
+        let head = format!("{}{name}", self.labels(node));
+        if values.is_empty() {
+            return vec![format!("{}{head};", indent(depth))];
+        }
+
+        let head = format!("{head} = ");
+        let offset = head.chars().count();
+        let mut lines = Vec::new();
+        let mut line = Line {
+            head,
+            ..Default::default()
+        };
+
+        for (i, value) in values.iter().enumerate() {
+            if i > 0 {
+                line.push(",");
+                if value.start_position().row > end_row(&values[i - 1]) {
+                    lines.push(std::mem::take(&mut line));
+                    line.offset = offset;
+                } else {
+                    line.flatten();
+                    line.head.push(' ');
+                }
+            }
+
+            match value.kind() {
+                "integer_cells" => {
+                    let cells = cells(value);
+                    if line.cells.is_some() || !line.tail.is_empty() {
+                        line.flatten();
+                    }
+
+                    line.head.push('<');
+                    line.cells = Some(Vec::new());
+                    let column = line.width();
+                    for (j, (start, end)) in cells.iter().enumerate() {
+                        if j > 0 && start.start_position().row > end_row(&cells[j - 1].1) {
+                            lines.push(std::mem::take(&mut line));
+                            line.offset = column;
+                            line.cells = Some(Vec::new());
+                        }
+                        let text = join_lines(&self.text[start.start_byte()..end.end_byte()]);
+                        line.cells.as_mut().unwrap().push(text);
+                    }
+                    line.tail.push('>');
+                }
+                "byte_string_literal" => {
+                    line.flatten();
+                    let text = self
+                        .text(value)
+                        .trim_start_matches('[')
+                        .trim_end_matches(']');
+                    let bytes: Vec<&str> = text.split_whitespace().collect();
+                    line.head.push('[');
+                    line.head.push_str(&bytes.join(" "));
+                    line.head.push(']');
+                }
+                _ => {
+                    line.flatten();
+                    line.head.push_str(&self.inline(value));
+                }
+            }
+        }
+        line.push(";");
+        lines.push(line);
+
+        // Rows of cells are aligned if they have the same layout, e.g. interrupt-map entries
+        let aligned = lines.len() > 1
+            && lines.iter().all(|x| {
+                x.width() == lines[0].width()
+                    && x.cells.as_ref().map(Vec::len).is_some_and(|n| n > 1)
+                    && x.cells.as_ref().map(Vec::len) == lines[0].cells.as_ref().map(Vec::len)
+            });
+        let mut widths = Vec::new();
+        if aligned {
+            for x in lines.iter().filter_map(|x| x.cells.as_ref()) {
+                widths.resize(x.len(), 0);
+                for (i, cell) in x.iter().enumerate() {
+                    widths[i] = widths[i].max(cell.chars().count());
+                }
+            }
+        }
+
+        lines.iter().map(|x| x.render(depth, &widths)).collect()
+    }
+
+    fn node(&self, node: &Node, depth: usize) -> Vec<String> {
+        let mut header = self.labels(node);
+        if let Some(name) = node.child_by_field_name("name") {
+            header.push_str(&self.inline(&name));
+        }
+        let mut cursor = node.walk();
+        if let Some(address) = node
+            .children_by_field_name("address", &mut cursor)
+            .find(|x| x.kind() == "unit_address")
+        {
+            header.push('@');
+            header.push_str(self.text(&address));
+        }
+
+        // Body is everything between braces
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node
+            .children(&mut cursor)
+            .skip_while(|x| x.kind() != "{")
+            .skip(1)
+            .take_while(|x| x.kind() != "}")
+            .filter(Node::is_named)
+            .collect();
+
+        let mut res = vec![format!("{}{header} {{", indent(depth))];
+        res.append(&mut self.body(children, depth + 1, true));
+        res.push(format!("{}}};", indent(depth)));
+        res
+    }
+
+    fn statement(&self, node: &Node, depth: usize) -> Vec<String> {
+        match node.kind() {
+            "node" => self.node(node, depth),
+            "property" => self.property(node, depth),
+            "omit_if_no_ref" => {
+                let Some(child) = node.named_child(0).filter(|x| x.kind() == "node") else {
+                    return self.verbatim(node, depth);
+                };
+                let mut lines = self.node(&child, depth);
+                lines[0] = format!(
+                    "{}/omit-if-no-ref/ {}",
+                    indent(depth),
+                    lines[0].trim_start()
+                );
+                lines
+            }
+            _ => self.verbatim(node, depth),
+        }
+    }
+
+    fn item(&self, item: &Item, depth: usize) -> Vec<String> {
+        let mut res = Vec::new();
+        for x in &item.leading {
+            res.append(&mut self.verbatim(x, depth));
+        }
+        res.append(&mut self.statement(&item.node, depth));
+        if let Some(x) = &item.trailing {
+            let last = res.last_mut().unwrap();
+            last.push(' ');
+            last.push_str(self.text(x).trim_end());
+        }
+        res
+    }
+
+    fn body(&self, children: Vec<Node>, depth: usize, reorder: bool) -> Vec<String> {
+        let mut items = items(children);
+
+        // Order is kept when it matters or it's not clear where comments belong
+        let reorder = reorder && items.iter().all(|x| is_statement(&x.node));
+        if reorder {
+            items.sort_by_key(|x| rank(&x.node, self.text));
+        }
+
+        let mut res = Vec::new();
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                let prev = &items[i - 1];
+                let nodes = is_node(&item.node) || is_node(&prev.node);
+                let blank = if nodes && is_statement(&item.node) && is_statement(&prev.node) {
+                    true
+                } else if reorder {
+                    false
+                } else {
+                    item.start_row() > prev.end_row() + 1
+                };
+                if blank {
+                    res.push(String::new());
+                }
+            }
+            res.append(&mut self.item(item, depth));
+        }
+        res
+    }
+}
+
+fn children<'t>(node: &Node<'t>) -> Vec<Node<'t>> {
+    let mut cursor = node.walk();
+    node.named_children(&mut cursor).collect()
+}
+
+// Formatted text of the whole document, None if document has syntax errors
+pub fn format(tree: &Tree, text: &str) -> Option<String> {
+    let root = tree.root_node();
+    if root.has_error() {
+        return None;
+    }
+
+    let formatter = Formatter { text };
+    let lines = formatter.body(children(&root), 0, false);
+    Some(lines.join("\n") + "\n")
+}
+
+// Edits formatting statements that intersect with the range
+pub fn format_range(tree: &Tree, text: &str, range: Range) -> Option<Vec<(Range, String)>> {
+    let root = tree.root_node();
+    if root.has_error() {
+        return None;
+    }
+
+    // Innermost node with the whole range inside of its body
+    let (start, end) = (range.start.line as usize, range.end.line as usize);
+    let mut container = root;
+    let mut depth = 0;
+    while let Some(x) = children(&container)
+        .into_iter()
+        .find(|x| x.kind() == "node" && x.start_position().row < start && end < end_row(x))
+    {
+        container = x;
+        depth += 1;
+    }
+
+    let body = if container.kind() == "node" {
+        let mut cursor = container.walk();
+        container
+            .children(&mut cursor)
+            .skip_while(|x| x.kind() != "{")
+            .skip(1)
+            .take_while(|x| x.kind() != "}")
+            .filter(Node::is_named)
+            .collect()
+    } else {
+        children(&container)
+    };
+
+    // Bottom to top, so that edits can be applied one by one
+    let formatter = Formatter { text };
+    let res = items(body)
+        .iter()
+        .rev()
+        .filter(|x| x.start_row() <= end && start <= x.end_row())
+        .map(|x| (x.range(), formatter.item(x, depth).join("\n")))
+        .collect();
+    Some(res)
+}
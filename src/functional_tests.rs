@@ -37,11 +37,21 @@ async fn make_backend(path: &str) -> Backend {
 
 impl Backend {
     async fn mock_initialize(&self, uri: Url) {
+        self.mock_initialize_ext(uri, ClientCapabilities::default())
+            .await;
+    }
+
+    async fn mock_initialize_ext(
+        &self,
+        uri: Url,
+        capabilities: ClientCapabilities,
+    ) -> InitializeResult {
         let params = InitializeParams {
             root_uri: Some(uri),
+            capabilities,
             ..Default::default()
         };
-        self.initialize(params).await.unwrap();
+        self.initialize(params).await.unwrap()
     }
 
     async fn mock_open(&self, uri: &str) {
@@ -123,12 +133,170 @@ impl Backend {
         self.rename(params).await
     }
 
+    // Emulate client accepting the rename, i.e. edit buffers and send didChange
+    async fn mock_apply(&self, edit: &Result<Option<WorkspaceEdit>>) {
+        let Some(DocumentChanges::Edits(changes)) =
+            &edit.as_ref().unwrap().as_ref().unwrap().document_changes
+        else {
+            panic!("Expected document changes");
+        };
+
+        // Client edits all buffers before notifying the server
+        let texts: Vec<String> = changes
+            .iter()
+            .map(|x| {
+                let text = self.data.fd.get_text(&x.text_document.uri).unwrap();
+                apply_text_edits(&text, &x.edits)
+            })
+            .collect();
+
+        for (change, text) in changes.iter().zip(texts) {
+            let uri = &change.text_document.uri;
+            let version = change.text_document.version.unwrap_or(0);
+
+            let params = DidChangeTextDocumentParams {
+                text_document: VersionedTextDocumentIdentifier::new(uri.clone(), version + 1),
+                content_changes: vec![TextDocumentContentChangeEvent {
+                    range: None,
+                    range_length: None,
+                    text,
+                }],
+            };
+            self.did_change(params).await;
+        }
+    }
+
+    async fn mock_formatting(
+        &self,
+        uri: &str,
+        range: Option<Range>,
+    ) -> Vec<OneOf<TextEdit, AnnotatedTextEdit>> {
+        let prefix = self.data.fd.get_root_dir().unwrap();
+        let text_document = TextDocumentIdentifier::new(prefix.join(uri).unwrap());
+        let options = FormattingOptions::default();
+        let work_done_progress_params = WorkDoneProgressParams::default();
+
+        let res = if let Some(range) = range {
+            let params = DocumentRangeFormattingParams {
+                text_document,
+                range,
+                options,
+                work_done_progress_params,
+            };
+            self.range_formatting(params).await
+        } else {
+            let params = DocumentFormattingParams {
+                text_document,
+                options,
+                work_done_progress_params,
+            };
+            self.formatting(params).await
+        };
+        res.unwrap().unwrap().into_iter().map(OneOf::Left).collect()
+    }
+
+    async fn mock_code_action(&self, uri: &str, range: Range) -> Vec<CodeAction> {
+        let prefix = self.data.fd.get_root_dir().unwrap();
+        let params = CodeActionParams {
+            text_document: TextDocumentIdentifier::new(prefix.join(uri).unwrap()),
+            range,
+            context: CodeActionContext::default(),
+            work_done_progress_params: WorkDoneProgressParams::default(),
+            partial_result_params: PartialResultParams::default(),
+        };
+
+        let res = self.code_action(params).await.unwrap().unwrap();
+        res.into_iter()
+            .filter_map(|x| match x {
+                CodeActionOrCommand::CodeAction(x) => Some(x),
+                CodeActionOrCommand::Command(_) => None,
+            })
+            .collect()
+    }
+
+    async fn mock_expand_macros(&self, uri: &str, range: Range) -> Option<String> {
+        let object = |items: Vec<(&str, LSPAny)>| {
+            LSPAny::Object(items.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+        };
+        let position = |x: Position| {
+            object(vec![
+                ("line", x.line.into()),
+                ("character", x.character.into()),
+            ])
+        };
+        let location = object(vec![
+            ("uri", self.make_url(uri).to_string().into()),
+            (
+                "range",
+                object(vec![
+                    ("start", position(range.start)),
+                    ("end", position(range.end)),
+                ]),
+            ),
+        ]);
+        let params = ExecuteCommandParams {
+            command: "dts-lsp.expandMacros".to_string(),
+            arguments: vec![location],
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        };
+        let res = self.execute_command(params).await.unwrap()?;
+        res.as_str().map(ToString::to_string)
+    }
+
+    // Differences in `path:property: message (file:line) source -> blob` form
+    async fn mock_compare_blob(&self, source: &str, blob: &str) -> Option<Vec<String>> {
+        let params = ExecuteCommandParams {
+            command: "dts-lsp.compareBlob".to_string(),
+            arguments: vec![
+                self.make_url(source).to_string().into(),
+                self.make_url(blob).to_string().into(),
+            ],
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        };
+        let res = self.execute_command(params).await.unwrap()?;
+        let prefix = self.data.fd.get_root_dir().unwrap().to_string();
+        let text = |x: &LSPAny, key: &str| x.get(key).and_then(LSPAny::as_str).map(str::to_string);
+        let differences = res.as_array()?.iter().map(|x| {
+            let property = text(x, "property").map(|x| format!(":{x}"));
+            let location = x.get("location").filter(|x| !x.is_null()).map(|x| {
+                let file = text(x, "uri").unwrap().replace(&prefix, "");
+                format!(" ({file}:{})", x["range"]["start"]["line"])
+            });
+            let values = match (text(x, "source"), text(x, "blob")) {
+                (Some(source), Some(blob)) => format!(" {source} -> {blob}"),
+                (None, Some(blob)) => format!(" {blob}"),
+                _ => String::new(),
+            };
+            format!(
+                "{}{}: {}{}{values}",
+                text(x, "path").unwrap(),
+                property.unwrap_or_default(),
+                text(x, "message").unwrap(),
+                location.unwrap_or_default(),
+            )
+        });
+        Some(differences.collect())
+    }
+
     async fn mock_refrences(&self, uri: &str, pos: Position) -> Result<Option<Vec<Location>>> {
+        self.mock_references_ext(uri, pos, false).await
+    }
+
+    async fn mock_references_ext(
+        &self,
+        uri: &str,
+        pos: Position,
+        include_declaration: bool,
+    ) -> Result<Option<Vec<Location>>> {
         let prefix = self.data.fd.get_root_dir().unwrap();
         let uri = prefix.join(uri).unwrap();
         let params = ReferenceParams {
             context: ReferenceContext {
-                include_declaration: false,
+                include_declaration,
             },
             text_document_position: TextDocumentPositionParams {
                 position: pos,
@@ -168,6 +336,42 @@ impl Backend {
         self.goto_definition(params).await
     }
 
+    async fn mock_hover(&self, uri: &str, pos: Position) -> Option<String> {
+        let params = HoverParams {
+            text_document_position_params: TextDocumentPositionParams {
+                position: pos,
+                text_document: TextDocumentIdentifier::new(self.make_url(uri)),
+            },
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+        };
+
+        match self.hover(params).await.unwrap()?.contents {
+            HoverContents::Markup(x) => Some(x.value),
+            _ => None,
+        }
+    }
+
+    async fn mock_inlay_hints(&self, uri: &str) -> Vec<(Position, String)> {
+        let params = InlayHintParams {
+            work_done_progress_params: WorkDoneProgressParams {
+                work_done_token: None,
+            },
+            text_document: TextDocumentIdentifier::new(self.make_url(uri)),
+            range: make_range((0, 0), (u32::MAX, 0)),
+        };
+
+        let hints = self.inlay_hint(params).await.unwrap().unwrap_or_default();
+        hints
+            .into_iter()
+            .map(|x| match x.label {
+                InlayHintLabel::String(label) => (x.position, label),
+                InlayHintLabel::LabelParts(_) => panic!("unexpected label parts"),
+            })
+            .collect()
+    }
+
     fn verify_file(&self, uri: &Url, expected_uri: &Url) -> bool {
         let text = self.data.fd.get_text(uri).unwrap();
         let expected_path = expected_uri.to_file_path().unwrap();
@@ -188,6 +392,17 @@ impl Backend {
         assert!(res);
     }
 
+    fn diagnostics(&self, path: &str) -> Vec<(Range, String)> {
+        let mut res: Vec<(Range, String)> = self
+            .data
+            .semantic_diagnostics(&self.make_url(path))
+            .into_iter()
+            .map(|x| (x.range, x.message))
+            .collect();
+        res.sort_by_key(|x| (x.0.start.line, x.0.start.character));
+        res
+    }
+
     fn verify_labels(&self, data: Vec<(&str, &str, Range)>) -> bool {
         self.data.ld.dump();
         let ld = LabelsDepot::new(&self.data.fd);
@@ -220,27 +435,60 @@ impl FileDepot {
     }
 }
 
-#[derive(Debug, PartialEq)]
-struct Changes(Result<Option<WorkspaceEdit>>, Url);
+struct Changes(Result<Option<WorkspaceEdit>>, FileDepot);
 
 impl Changes {
     // TODO: add new_expected
-    fn new(path: Url) -> Self {
-        Self(Ok(Some(WorkspaceEdit::new(HashMap::new()))), path)
+    fn new(fd: &FileDepot) -> Self {
+        let edit = WorkspaceEdit {
+            document_changes: Some(DocumentChanges::Edits(Vec::new())),
+            ..Default::default()
+        };
+        Self(Ok(Some(edit)), fd.clone())
     }
     fn add_edit(&mut self, uri: &str, begin: (u32, u32), end: (u32, u32), new_text: &str) {
-        let uri = self.1.join(uri).unwrap();
+        let uri = self.1.get_root_dir().unwrap().join(uri).unwrap();
 
         let x = self.0.as_mut().unwrap().as_mut().unwrap();
-        let changes = x.changes.get_or_insert(HashMap::new());
+        let Some(DocumentChanges::Edits(changes)) = &mut x.document_changes else {
+            unreachable!();
+        };
 
-        changes.entry(uri).or_default().push(TextEdit {
-            range: Range::new(Position::new(begin.0, begin.1), Position::new(end.0, end.1)),
-            new_text: new_text.to_string(),
-        });
+        if changes.last().is_none_or(|x| x.text_document.uri != uri) {
+            changes.push(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    version: self.1.get_version(&uri),
+                    uri: uri.clone(),
+                },
+                edits: Vec::new(),
+            });
+        }
+
+        changes
+            .last_mut()
+            .unwrap()
+            .edits
+            .push(OneOf::Left(TextEdit {
+                range: Range::new(Position::new(begin.0, begin.1), Position::new(end.0, end.1)),
+                new_text: new_text.to_string(),
+            }));
     }
 }
 
+// Apply edits the way client does, edits don't overlap and are ordered bottom to top
+fn apply_text_edits(text: &str, edits: &[OneOf<TextEdit, AnnotatedTextEdit>]) -> String {
+    let mut text = text.to_string();
+    for edit in edits {
+        let OneOf::Left(edit) = edit else {
+            unreachable!();
+        };
+        let range = encoding::range_from_client(&text, edit.range, Encoding::Utf16);
+        let range = encoding::offset(&text, range.start)..encoding::offset(&text, range.end);
+        text.replace_range(range, &edit.new_text);
+    }
+    text
+}
+
 type Diagnostic = (MessageType, String);
 
 fn validate_messages(rx: &mpsc::Receiver<Diagnostic>, v: Vec<Diagnostic>) {
@@ -421,7 +669,7 @@ async fn rename_0() {
     let res = be.mock_rename(path, Position::new(1, 2), "changed").await;
     assert!(res.is_err());
 
-    let mut expected = Changes::new(be.data.fd.get_root_dir().unwrap());
+    let mut expected = Changes::new(&be.data.fd);
     expected.add_edit(path, (1, 1), (1, 2), "changed");
     let res = be.mock_rename(path, Position::new(1, 1), "changed").await;
     assert_eq!(expected.0, res);
@@ -452,11 +700,18 @@ async fn rename_1() {
     assert!(res.is_ok());
 
     let res = be.mock_rename(path, Position::new(3, 1), "changed").await;
-    let mut expected = Changes::new(be.data.fd.get_root_dir().unwrap());
+    let mut expected = Changes::new(&be.data.fd);
     expected.add_edit("a.dts", (3, 1), (3, 5), "changed");
     expected.add_edit("common.dtsi", (2, 10), (2, 14), "changed");
     assert_eq!(expected.0, res);
 
+    // Index is not changed until client applies the edit
+    assert!(be.verify_labels(vec![
+        ("node", "a.dts", make_range((3, 1), (3, 5))),
+        ("node", "b.dts", make_range((2, 1), (2, 5))),
+    ]));
+
+    be.mock_apply(&res).await;
     assert_eq!(be.data.fd.size(), 3);
 
     assert!(be.verify_labels(vec![
@@ -493,12 +748,13 @@ async fn rename_2() {
     assert!(res.is_ok());
 
     let res = be.mock_rename(path, Position::new(2, 10), "changed").await;
-    let mut expected = Changes::new(be.data.fd.get_root_dir().unwrap());
+    let mut expected = Changes::new(&be.data.fd);
     expected.add_edit("a.dts", (3, 1), (3, 5), "changed");
     expected.add_edit("b.dts", (2, 1), (2, 5), "changed");
     expected.add_edit("common.dtsi", (2, 10), (2, 14), "changed");
     assert_eq!(expected.0, res);
 
+    be.mock_apply(&res).await;
     assert_eq!(be.data.fd.size(), 3);
     assert_eq!(be.data.ld.size(), 2);
     assert_eq!(be.data.rd.size(), 1);
@@ -548,17 +804,20 @@ async fn rename_3() {
 
     be.mock_open(path).await;
 
-    let _ = be.mock_rename(path, Position::new(1, 1), "lbl").await;
+    let res = be.mock_rename(path, Position::new(1, 1), "lbl").await;
+    be.mock_apply(&res).await;
     assert!(be.verify_file(&be.make_url(path), &be.make_url("after-2.dts")));
 
-    let _ = be
+    let res = be
         .mock_rename(path, Position::new(1, 1), "some_label")
         .await;
+    be.mock_apply(&res).await;
     assert!(be.verify_file(&be.make_url(path), &be.make_url("before.dts")));
 
-    let _ = be
+    let res = be
         .mock_rename(path, Position::new(1, 1), "very_long_label_value")
         .await;
+    be.mock_apply(&res).await;
     assert!(be.verify_file(&be.make_url(path), &be.make_url("after-4.dts")));
 }
 
@@ -654,3 +913,1148 @@ async fn goto_definition_1() {
     let loc = Location::new(be.make_url("base_base.dtsi"), make_range((2, 1), (2, 10)));
     assert_eq!(res.unwrap().unwrap(), GotoDefinitionResponse::Scalar(loc));
 }
+
+#[tokio::test]
+async fn diagnostics_0() {
+    let be = &make_backend("tests/diagnostics/").await;
+
+    be.mock_open("board.dts").await;
+
+    assert_eq!(
+        be.diagnostics("board.dts"),
+        vec![
+            (
+                make_range((10, 40), (10, 47)),
+                "Undefined label: missing".to_string()
+            ),
+            (
+                make_range((10, 50), (10, 62)),
+                "Unresolved macro: UNKNOWN_FLAG".to_string()
+            ),
+            (
+                make_range((14, 1), (14, 4)),
+                "Duplicate label: dup".to_string()
+            ),
+            (
+                make_range((23, 0), (25, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        be.diagnostics("soc.dtsi"),
+        vec![(
+            make_range((9, 2), (9, 5)),
+            "Duplicate label: dup".to_string()
+        )]
+    );
+
+    assert_eq!(be.diagnostics("other.dts"), vec![]);
+}
+
+#[tokio::test]
+async fn cells_0() {
+    let be = &make_backend("tests/cells/").await;
+
+    be.mock_open("board.dts").await;
+
+    assert_eq!(
+        be.diagnostics("board.dts"),
+        vec![
+            (
+                make_range((14, 2), (14, 28)),
+                "ranges has 3 cells, expected a multiple of 2 (child #address-cells = <1>, \
+                parent #address-cells = <1>, #size-cells = <0>)"
+                    .to_string()
+            ),
+            (
+                make_range((22, 29), (22, 33)),
+                "clocks expects 1 argument(s) (#clock-cells = <1>), got 0".to_string()
+            ),
+            (
+                make_range((23, 27), (23, 28)),
+                "Too many arguments for &gpio in reset-gpios (#gpio-cells = <2>)".to_string()
+            ),
+            (
+                make_range((25, 12), (25, 16)),
+                "&clk has no #reset-cells property".to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        be.diagnostics("soc.dtsi"),
+        vec![(
+            make_range((5, 2), (5, 17)),
+            "reg has 1 cells, expected a multiple of 2 (#address-cells = <1>, \
+            #size-cells = <1> in parent)"
+                .to_string()
+        )]
+    );
+
+    // Cached trees are rebuilt when included file changes
+    let text = read_to_string(be.make_url("soc.dtsi").to_file_path().unwrap()).unwrap();
+    be.mock_change("soc.dtsi", text.replace("<0x2000>", "<0x2000 0x100>"))
+        .await;
+    assert_eq!(be.diagnostics("soc.dtsi"), vec![]);
+}
+
+#[tokio::test]
+async fn unit_address_0() {
+    let be = &make_backend("tests/unit_address/").await;
+
+    be.mock_open("board.dts").await;
+
+    assert_eq!(
+        be.diagnostics("board.dts"),
+        vec![
+            (
+                make_range((11, 1), (11, 16)),
+                "Unit address does not match reg address, expected \"40013000\"".to_string()
+            ),
+            (
+                make_range((15, 1), (15, 11)),
+                "Unit address should not have leading zeros".to_string()
+            ),
+            (
+                make_range((19, 1), (19, 10)),
+                "Unit address should be in lowercase".to_string()
+            ),
+            (
+                make_range((23, 1), (23, 7)),
+                "Node has a reg or ranges property, but no unit address".to_string()
+            ),
+            (
+                make_range((27, 1), (27, 7)),
+                "Node has a unit address, but no reg or ranges property".to_string()
+            ),
+            (
+                make_range((30, 1), (30, 13)),
+                "Duplicate unit address (also used in node i2c@50000000)".to_string()
+            ),
+            (
+                make_range((31, 9), (31, 24)),
+                "Memory region 0x50000000-0x5000000f overlaps with /i2c@50000000 \
+                (0x50000000-0x5000000f)"
+                    .to_string()
+            ),
+            (
+                make_range((34, 1), (34, 13)),
+                "Duplicate unit address (also used in node spi@50000000)".to_string()
+            ),
+            (
+                make_range((35, 9), (35, 24)),
+                "Memory region 0x50000000-0x5000000f overlaps with /spi@50000000 \
+                (0x50000000-0x5000000f)"
+                    .to_string()
+            ),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn overlays_0() {
+    let be = &make_backend("tests/overlays/").await;
+    let path = "board.dtso";
+
+    be.data.fd.set_overlay_base("base.dts");
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((11, 3), (11, 12)),
+                "Unit address does not match reg address, expected \"40\"".to_string()
+            ),
+            (
+                make_range((18, 16), (18, 30)),
+                "Unresolved overlay target: &{/soc/missing}".to_string()
+            ),
+            (
+                make_range((24, 11), (24, 25)),
+                "Unresolved overlay target: &nonexistent".to_string()
+            ),
+            (
+                make_range((30, 0), (30, 14)),
+                "Unresolved overlay target: &missing_label".to_string()
+            ),
+        ]
+    );
+    assert_eq!(be.diagnostics("base.dts"), vec![]);
+
+    let res = be.mock_goto_definition(path, Position::new(3, 1)).await;
+    let loc = Location::new(be.make_url("base.dts"), make_range((6, 1), (6, 6)));
+    assert_eq!(res.unwrap().unwrap(), GotoDefinitionResponse::Scalar(loc));
+}
+
+#[tokio::test]
+async fn overlays_1() {
+    // Without base tree overlay targets can't be verified
+    let be = &make_backend("tests/overlays/").await;
+    let path = "board.dtso";
+
+    be.mock_open(path).await;
+
+    assert_eq!(be.diagnostics(path), vec![]);
+}
+
+#[tokio::test]
+async fn interrupts_0() {
+    let be = &make_backend("tests/interrupts/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![(
+            make_range((48, 17), (48, 18)),
+            "No interrupt-controller is reachable for the interrupt".to_string()
+        )]
+    );
+    assert_eq!(
+        be.diagnostics("other.dts"),
+        vec![
+            (
+                make_range((4, 2), (4, 19)),
+                "Node has interrupts, but no interrupt parent".to_string()
+            ),
+            (
+                make_range((14, 22), (14, 27)),
+                "Unresolved macro: CELLS".to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        be.mock_hover(path, Position::new(19, 3)).await.unwrap(),
+        "**interrupts**\n\n\
+        Interrupt parent: `/interrupt-controller@1000`\n\n\
+        - `<GIC_SPI 5 IRQ_TYPE_LEVEL_HIGH>` → `/interrupt-controller@1000`: \
+        SPI 5 (hwirq 37), level high"
+    );
+    assert_eq!(
+        be.mock_hover(path, Position::new(30, 3)).await.unwrap(),
+        "**interrupts-extended**\n\n\
+        - `<&gpio 3 2>` → `/gpio@3000`: IRQ 3, edge falling"
+    );
+    assert_eq!(
+        be.mock_hover(path, Position::new(43, 3)).await.unwrap(),
+        "**interrupts**\n\n\
+        Interrupt parent: `/pci@4000`\n\n\
+        - `<2>` → `/interrupt-controller@1000`: SPI 11 (hwirq 43), level high \
+        via `/pci@4000`"
+    );
+    assert_eq!(
+        be.mock_hover(path, Position::new(54, 3)).await.unwrap(),
+        "**interrupts**\n\n\
+        Interrupt parent: `/interrupt-controller@1000`\n\n\
+        - `<0 (-1) IRQ_TYPE_LEVEL_HIGH>` → `/interrupt-controller@1000`: \
+        SPI 4294967295 (hwirq 4294967327), level high"
+    );
+
+    let res = be.mock_goto_definition(path, Position::new(19, 24)).await;
+    let loc = Location::new(be.make_url(path), make_range((10, 6), (10, 31)));
+    assert_eq!(
+        res.unwrap().unwrap(),
+        GotoDefinitionResponse::Scalar(loc.clone())
+    );
+
+    let res = be.mock_goto_definition(path, Position::new(43, 17)).await;
+    assert_eq!(res.unwrap().unwrap(), GotoDefinitionResponse::Scalar(loc));
+}
+
+#[tokio::test]
+async fn addresses_0() {
+    let be = &make_backend("tests/addresses/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((17, 10), (17, 22)),
+                "Memory region 0x40001000-0x400010ff overlaps with /soc@40000000/timer@1080 \
+                (0x40001080-0x4000117f)"
+                    .to_string()
+            ),
+            (
+                make_range((21, 10), (21, 22)),
+                "Memory region 0x40001080-0x4000117f overlaps with /soc@40000000/uart@1000 \
+                (0x40001000-0x400010ff)"
+                    .to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        be.mock_hover(path, Position::new(39, 4)).await.unwrap(),
+        "**reg**\n\nCPU addresses:\n\
+        - `<0x20 0x10>` → `0x40003020-0x4000302f`\n\
+        - `<0x100 0x10>` → `0x40003100-0x4000310f`"
+    );
+    assert_eq!(
+        be.mock_hover(path, Position::new(49, 4)).await.unwrap(),
+        "**reg**\n\nCPU addresses:\n\
+        - `<0x10 0x10>`: not mapped to CPU address space"
+    );
+
+    assert_eq!(
+        be.mock_inlay_hints(path).await,
+        vec![
+            (Position::new(17, 22), "→ 0x40001000".to_string()),
+            (Position::new(21, 22), "→ 0x40001080".to_string()),
+            (Position::new(25, 22), "→ 0x40002000".to_string()),
+            (Position::new(30, 22), "→ 0x40002080".to_string()),
+            (Position::new(39, 20), "→ 0x40003020".to_string()),
+            (Position::new(39, 34), "→ 0x40003100".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn inlay_hints_0() {
+    let be = &make_backend("tests/inlay_hints/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+
+    let mut hints = be.mock_inlay_hints(path).await;
+    hints.sort_by_key(|x| (x.0.line, x.0.character));
+    assert_eq!(
+        hints,
+        vec![
+            (Position::new(13, 23), "pin:".to_string()),
+            (Position::new(13, 25), "flags:".to_string()),
+            (Position::new(13, 40), "= 1".to_string()),
+            (Position::new(14, 17), "clock:".to_string()),
+            (Position::new(14, 25), "= 3".to_string()),
+            (Position::new(15, 25), "port:".to_string()),
+            (Position::new(15, 30), "line:".to_string()),
+            (Position::new(15, 33), "mode:".to_string()),
+            (Position::new(15, 36), "= 0x7".to_string()),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn rename_macro_0() {
+    let be = &make_backend("tests/macros/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+
+    let res = be.mock_rename_prepare(path, Position::new(6, 18)).await;
+    assert!(res.is_ok());
+
+    let res = be.mock_rename(path, Position::new(6, 18), "CLK_SPI").await;
+    assert_eq!(res.unwrap_err().message, "Macro CLK_SPI is already defined");
+
+    let res = be.mock_rename(path, Position::new(6, 18), "CLK_SYS").await;
+    let mut expected = Changes::new(&be.data.fd);
+    expected.add_edit("board.dts", (6, 35), (6, 43), "CLK_SYS");
+    expected.add_edit("board.dts", (6, 17), (6, 25), "CLK_SYS");
+    expected.add_edit("defs.h", (1, 17), (1, 25), "CLK_SYS");
+    expected.add_edit("defs.h", (0, 8), (0, 16), "CLK_SYS");
+    expected.add_edit("soc.dtsi", (5, 7), (5, 15), "CLK_SYS");
+    expected.add_edit("soc.dtsi", (3, 26), (3, 34), "CLK_SYS");
+    assert_eq!(expected.0, res);
+
+    let url = be.make_url(path);
+    assert!(be.data.id.find_define(&url, "CLK_SYS").is_none());
+
+    be.mock_apply(&res).await;
+    assert!(be.data.id.find_define(&url, "CLK_MAIN").is_none());
+    assert!(be.data.id.find_define(&url, "CLK_SYS").is_some());
+    let other = be.make_url("other.dts");
+    assert!(be.data.id.find_define(&other, "CLK_MAIN").is_some());
+}
+
+#[tokio::test]
+async fn references_macro_0() {
+    let be = &make_backend("tests/macros/").await;
+
+    be.mock_open("board.dts").await;
+    assert_eq!(be.data.md.size(), 4);
+
+    let expected = vec![
+        Location::new(be.make_url("board.dts"), make_range((6, 17), (6, 25))),
+        Location::new(be.make_url("board.dts"), make_range((6, 35), (6, 43))),
+        Location::new(be.make_url("defs.h"), make_range((1, 17), (1, 25))),
+        Location::new(be.make_url("soc.dtsi"), make_range((3, 26), (3, 34))),
+        Location::new(be.make_url("soc.dtsi"), make_range((5, 7), (5, 15))),
+    ];
+
+    let res = be.mock_refrences("defs.h", Position::new(0, 8)).await;
+    assert_eq!(res.unwrap().unwrap(), expected);
+
+    let res = be.mock_refrences("board.dts", Position::new(6, 18)).await;
+    assert_eq!(res.unwrap().unwrap(), expected);
+
+    // Macro with the same name in unrelated file
+    let res = be.mock_refrences("other.dts", Position::new(4, 7)).await;
+    let other = be.make_url("other.dts");
+    assert_eq!(
+        res.unwrap().unwrap(),
+        vec![Location::new(other, make_range((4, 6), (4, 14)))]
+    );
+}
+
+#[tokio::test]
+async fn references_1() {
+    let be = &make_backend("tests/references/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+
+    let loc = |uri, start, end| Location::new(be.make_url(uri), make_range(start, end));
+
+    // Label and path references resolve to the same node, from usage inside of cells and from
+    // override block
+    let expected = vec![
+        loc("board.dts", (6, 13), (6, 16)),
+        loc("board.dts", (6, 24), (6, 50)),
+        loc("board.dts", (10, 1), (10, 4)),
+        loc("board.dts", (14, 2), (14, 28)),
+    ];
+    let positions = [
+        Position::new(6, 14),
+        Position::new(10, 2),
+        Position::new(6, 30),
+        Position::new(14, 5),
+    ];
+    for pos in positions {
+        let res = be.mock_refrences(path, pos).await;
+        assert_eq!(res.unwrap().unwrap(), expected);
+    }
+
+    let mut expected = expected;
+    expected.push(loc("soc.dtsi", (5, 2), (5, 5)));
+    expected.push(loc("soc.dtsi", (5, 7), (5, 28)));
+    for pos in positions {
+        let res = be.mock_references_ext(path, pos, true).await;
+        assert_eq!(res.unwrap().unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn rename_4() {
+    // Positions are in UTF-16 and open documents are versioned
+    let be = &make_backend("tests/rename/").await;
+    let path = "board.dts";
+
+    be.mock_open(path).await;
+    let text = be.data.fd.get_text(&be.make_url(path)).unwrap();
+    be.mock_change(path, text).await;
+
+    let res = be.mock_rename(path, Position::new(5, 22), "button").await;
+    let mut expected = Changes::new(&be.data.fd);
+    expected.add_edit("board.dts", (5, 20), (5, 23), "button");
+    expected.add_edit("keys.dtsi", (2, 18), (2, 21), "button");
+    assert_eq!(expected.0, res);
+
+    let Some(DocumentChanges::Edits(changes)) =
+        &res.as_ref().unwrap().as_ref().unwrap().document_changes
+    else {
+        unreachable!();
+    };
+    let versions: Vec<_> = changes.iter().map(|x| x.text_document.version).collect();
+    assert_eq!(versions, vec![Some(2), None]);
+
+    be.mock_apply(&res).await;
+    assert_eq!(be.has_label(path, "button"), 1);
+    assert_eq!(be.has_label(path, "btn"), 0);
+}
+
+#[tokio::test]
+async fn rename_5() {
+    // Overlapping edits fail the whole edit instead of applying part of it
+    let be = &make_backend("tests/rename/").await;
+    let path = "board.dts";
+    be.mock_open(path).await;
+
+    let uri = be.make_url(path);
+    let edits = vec![
+        TextEdit::new(make_range((5, 20), (5, 23)), "button".to_string()),
+        TextEdit::new(make_range((5, 22), (5, 24)), "x".to_string()),
+    ];
+    let res = be
+        .data
+        .workspace_edit(HashMap::from([(uri.clone(), edits)]));
+    assert_eq!(
+        res.unwrap_err(),
+        format!("Overlapping edits in {uri} at line 6")
+    );
+}
+
+#[tokio::test]
+async fn encoding_0() {
+    let be = &make_backend("tests/rename/").await;
+    let path = "keys.dtsi";
+    let loc = |uri, start, end| Location::new(be.make_url(uri), make_range(start, end));
+
+    be.mock_open("board.dts").await;
+
+    // UTF-16 is used when client doesn't support anything else
+    let res = be
+        .mock_references_ext(path, Position::new(2, 19), true)
+        .await;
+    let expected = vec![
+        loc("board.dts", (5, 20), (5, 23)),
+        loc("board.dts", (5, 25), (5, 31)),
+        loc("keys.dtsi", (2, 18), (2, 21)),
+    ];
+    assert_eq!(res.unwrap().unwrap(), expected);
+
+    let capabilities = ClientCapabilities {
+        general: Some(GeneralClientCapabilities {
+            position_encodings: Some(vec![
+                PositionEncodingKind::UTF16,
+                PositionEncodingKind::UTF8,
+            ]),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let root = be.data.fd.get_root_dir().unwrap();
+    let res = be.mock_initialize_ext(root, capabilities).await;
+    assert_eq!(
+        res.capabilities.position_encoding,
+        Some(PositionEncodingKind::UTF8)
+    );
+
+    let res = be
+        .mock_references_ext(path, Position::new(2, 21), true)
+        .await;
+    let expected = vec![
+        loc("board.dts", (5, 21), (5, 24)),
+        loc("board.dts", (5, 26), (5, 32)),
+        loc("keys.dtsi", (2, 20), (2, 23)),
+    ];
+    assert_eq!(res.unwrap().unwrap(), expected);
+}
+
+#[tokio::test]
+async fn formatting_0() {
+    let be = &make_backend("tests/formatting/").await;
+    let path = "before.dts";
+    let expected = read_to_string(be.make_url("after.dts").to_file_path().unwrap()).unwrap();
+
+    be.mock_open(path).await;
+    let text = be.data.fd.get_text(&be.make_url(path)).unwrap();
+
+    let edits = be.mock_formatting(path, None).await;
+    assert_eq!(apply_text_edits(&text, &edits), expected);
+
+    // Only statements inside of the range are changed
+    let edits = be
+        .mock_formatting(path, Some(make_range((22, 3), (23, 4))))
+        .await;
+    let mut expected = text.clone();
+    expected = expected.replace(
+        "\t\t\tinterrupt-map = <0 0 0 1 &intc 5>,\n\t\t\t<0x800",
+        "\t\t\tinterrupt-map = <0     0 0 1 &intc 5>,\n\t\t\t                <0x800",
+    );
+    assert_eq!(apply_text_edits(&text, &edits), expected);
+
+    // Formatted document is not changed
+    be.mock_open("after.dts").await;
+    assert!(be.mock_formatting("after.dts", None).await.is_empty());
+}
+
+#[tokio::test]
+async fn code_action_0() {
+    let be = &make_backend("tests/code_actions/").await;
+    let path = "board.dts";
+
+    be.mock_open("include/dt-bindings/gpio/gpio.h").await;
+    be.mock_open(path).await;
+    let quickfixes = |x: Vec<CodeAction>| {
+        x.into_iter()
+            .filter(|x| x.kind == Some(CodeActionKind::QUICKFIX))
+            .collect::<Vec<_>>()
+    };
+    let quickfixes_at = |x: Range| async move { quickfixes(be.mock_code_action(path, x).await) };
+
+    // Macro is resolved through local include
+    let actions = quickfixes_at(make_range((11, 19), (11, 19))).await;
+    assert!(actions.is_empty());
+
+    let actions = quickfixes_at(make_range((11, 30), (11, 30))).await;
+    assert_eq!(actions.len(), 1);
+    assert_eq!(actions[0].title, "Add #include <dt-bindings/gpio/gpio.h>");
+    assert_eq!(actions[0].kind, Some(CodeActionKind::QUICKFIX));
+
+    let text = be.data.fd.get_text(&be.make_url(path)).unwrap();
+    be.mock_apply(&Ok(actions[0].edit.clone())).await;
+    let expected = text.replace(
+        "#include \"defs.h\"\n",
+        "#include \"defs.h\"\n#include <dt-bindings/gpio/gpio.h>\n",
+    );
+    assert_eq!(be.data.fd.get_text(&be.make_url(path)).unwrap(), expected);
+
+    let actions = quickfixes_at(make_range((0, 0), (15, 0))).await;
+    assert!(actions.is_empty());
+}
+
+#[tokio::test]
+async fn code_action_1() {
+    let be = &make_backend("tests/node_actions/").await;
+    let board = "board.dts";
+    let soc = "soc.dtsi";
+    be.mock_open(board).await;
+
+    let titles = |x: &Vec<CodeAction>| x.iter().map(|x| x.title.clone()).collect::<Vec<_>>();
+    let text = |x: &str| be.data.fd.get_text(&be.make_url(x)).unwrap();
+
+    let actions = be.mock_code_action(board, make_range((5, 3), (5, 3))).await;
+    assert_eq!(titles(&actions), vec!["Set status to \"disabled\""]);
+    let expected = text(board).replace("\"okay\"", "\"disabled\"");
+    be.mock_apply(&Ok(actions[0].edit.clone())).await;
+    assert_eq!(text(board), expected);
+
+    // Target node gets a label
+    let actions = be.mock_code_action(board, make_range((8, 3), (8, 3))).await;
+    assert_eq!(
+        titles(&actions),
+        vec!["Set status to \"disabled\"", "Convert to &i2c override"]
+    );
+    let expected_board = text(board).replace("&{/soc/i2c@2000}", "&i2c");
+    let expected_soc = text(soc).replace("\ti2c@2000", "\ti2c: i2c@2000");
+    be.mock_apply(&Ok(actions[1].edit.clone())).await;
+    assert_eq!(text(board), expected_board);
+    assert_eq!(text(soc), expected_soc);
+
+    // Properties are added to existing override
+    let actions = be.mock_code_action(soc, make_range((5, 3), (5, 3))).await;
+    assert_eq!(
+        titles(&actions),
+        vec![
+            "Set status to \"okay\"",
+            "Move properties to &uart0 in board.dts"
+        ]
+    );
+    let expected_board = text(board).replace(
+        "\"disabled\";\n};",
+        "\"disabled\";\n\tclock-frequency = <48000000>;\n};",
+    );
+    let expected_soc = text(soc).replace("\t\t\tclock-frequency = <48000000>;\n", "");
+    be.mock_apply(&Ok(actions[1].edit.clone())).await;
+    assert_eq!(text(board), expected_board);
+    assert_eq!(text(soc), expected_soc);
+
+    // New override is created in the board file
+    let actions = be.mock_code_action(soc, make_range((1, 1), (1, 1))).await;
+    assert_eq!(
+        titles(&actions),
+        vec!["Set status to \"disabled\"", "Add label soc"]
+    );
+    // Selection of reg, which is one line up after clock-frequency has been moved
+    let actions = be.mock_code_action(soc, make_range((15, 0), (16, 0))).await;
+    assert_eq!(actions[1].title, "Move properties to &spi0 in board.dts");
+    be.mock_apply(&Ok(actions[1].edit.clone())).await;
+    assert!(text(board).ends_with("};\n\n&spi0 {\n\treg = <0x3000 0x100>;\n};\n"));
+    assert!(
+        text(soc).contains("spi0: spi@3000 {\n\t\t\tcompatible = \"vendor,spi\";\n\t\t\tstatus")
+    );
+
+    // Only the selected property is removed from the line
+    let line = text(soc)
+        .lines()
+        .position(|x| x.contains("ngpios"))
+        .unwrap();
+    let line = u32::try_from(line).unwrap();
+    let actions = be
+        .mock_code_action(soc, make_range((line, 4), (line, 4)))
+        .await;
+    assert_eq!(actions[1].title, "Move properties to &gpio0 in board.dts");
+    be.mock_apply(&Ok(actions[1].edit.clone())).await;
+    assert!(text(board).ends_with("};\n\n&gpio0 {\n\treg = <0x4000 0x100>;\n};\n"));
+    assert!(text(soc).contains("gpio0: gpio@4000 {\n\t\t\tngpios = <32>;\n\t\t};"));
+}
+
+#[tokio::test]
+async fn folding_0() {
+    let be = &make_backend("tests/folding/").await;
+    let path = "soc.dtsi";
+    be.mock_open(path).await;
+
+    let params = FoldingRangeParams {
+        text_document: TextDocumentIdentifier::new(be.make_url(path)),
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+    };
+    let res = be.folding_range(params).await.unwrap().unwrap();
+    let res: Vec<_> = res
+        .into_iter()
+        .map(|x| (x.start_line, x.end_line, x.kind))
+        .collect();
+
+    let expected = vec![
+        (0, 1, Some(FoldingRangeKind::Imports)),
+        (3, 5, Some(FoldingRangeKind::Comment)),
+        (6, 24, None),
+        (7, 23, None),
+        (8, 9, None),
+        (12, 14, None),
+        (13, 14, None),
+        (17, 20, Some(FoldingRangeKind::Region)),
+        (18, 19, None),
+        (21, 22, Some(FoldingRangeKind::Region)),
+    ];
+    assert_eq!(res, expected);
+}
+
+#[tokio::test]
+async fn selection_range_0() {
+    let be = &make_backend("tests/folding/").await;
+    let path = "soc.dtsi";
+    be.mock_open(path).await;
+
+    let params = SelectionRangeParams {
+        text_document: TextDocumentIdentifier::new(be.make_url(path)),
+        positions: vec![Position::new(13, 30)],
+        work_done_progress_params: WorkDoneProgressParams::default(),
+        partial_result_params: PartialResultParams::default(),
+    };
+    let res = be.selection_range(params).await.unwrap().unwrap();
+
+    let mut ranges = Vec::new();
+    let mut cur = res.first();
+    while let Some(x) = cur {
+        ranges.push(x.range);
+        cur = x.parent.as_deref();
+    }
+
+    // Label, reference, cells, property, node body, node, soc body, soc, root body, root, document
+    let expected = vec![
+        make_range((13, 29), (13, 33)),
+        make_range((13, 28), (13, 33)),
+        make_range((13, 19), (13, 36)),
+        make_range((13, 3), (14, 23)),
+        make_range((12, 34), (15, 3)),
+        make_range((12, 2), (15, 4)),
+        make_range((7, 5), (24, 2)),
+        make_range((7, 1), (24, 3)),
+        make_range((6, 2), (25, 1)),
+        make_range((6, 0), (25, 2)),
+        make_range((0, 0), (26, 0)),
+    ];
+    assert_eq!(ranges, expected);
+}
+
+#[tokio::test]
+async fn values_0() {
+    let be = &make_backend("tests/values/").await;
+    let path = "board.dts";
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((10, 25), (10, 30)),
+                "Value 0x1ff does not fit in 8 bits".to_string()
+            ),
+            (
+                make_range((11, 45), (11, 50)),
+                "References are not allowed in 16-bit cells".to_string()
+            ),
+            (
+                make_range((12, 11), (12, 24)),
+                "Division by zero".to_string()
+            ),
+            (
+                make_range((12, 25), (12, 32)),
+                "Division by zero".to_string()
+            ),
+            (
+                make_range((13, 8), (13, 20)),
+                "Malformed byte string, expected pairs of hex digits".to_string()
+            ),
+            (
+                make_range((18, 15), (18, 17)),
+                "Only 8, 16, 32 and 64-bit cells are supported".to_string()
+            ),
+        ]
+    );
+
+    let hover = |line| async move { be.mock_hover(path, Position::new(line, 3)).await.unwrap() };
+    assert_eq!(
+        hover(10).await,
+        "**bytes**: 8-bit cells\n\n- `<0x12 0x1ff (-1)>` = `<0x12 0xff 0xff>`"
+    );
+    assert_eq!(
+        hover(11).await,
+        "**words**: 16-bit cells\n\n- `<(BASE + 0x100) (1 << 3) &leds>` = `<0x1100 0x8 &leds>`"
+    );
+    assert_eq!(
+        hover(12).await,
+        "**cells**: 32-bit cells\n\n\
+        - `<(BASE / ZERO) (7 % 0) (10 - 2 * 3)>` = `<(BASE / ZERO) (7 % 0) 0x4>`"
+    );
+    assert_eq!(hover(13).await, "**mac**: malformed");
+    assert_eq!(
+        hover(14).await,
+        "**data**: byte string\n\n- `[00 11 aa bb]` (4 bytes)"
+    );
+    assert_eq!(
+        hover(15).await,
+        "**names**: string list\n\n- `\"a\"`\n- `\"b\"`"
+    );
+    assert_eq!(hover(16).await, "**path**: path\n\n- `&leds` → `\"/leds\"`");
+    assert_eq!(hover(17).await, "**empty**: empty");
+}
+
+#[tokio::test]
+async fn expressions_0() {
+    let be = &make_backend("tests/expressions/").await;
+    let path = "board.dts";
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((10, 11), (10, 17)),
+                "Recursive macro: LOOP_A → LOOP_B → LOOP_A".to_string()
+            ),
+            (
+                make_range((19, 0), (22, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        be.diagnostics("defs.h"),
+        vec![
+            (
+                make_range((7, 8), (7, 14)),
+                "Recursive macro: LOOP_A → LOOP_B → LOOP_A".to_string()
+            ),
+            (
+                make_range((8, 8), (8, 14)),
+                "Recursive macro: LOOP_B → LOOP_A → LOOP_B".to_string()
+            ),
+        ]
+    );
+
+    assert_eq!(
+        be.mock_hover(path, Position::new(9, 3)).await.unwrap(),
+        "**reg**\n\nCPU addresses:\n- `<REG(2) STRIDE>` → `0x1020-0x102f`"
+    );
+
+    let mut hints = be.mock_inlay_hints(path).await;
+    hints.sort_by_key(|x| (x.0.line, x.0.character));
+    assert!(hints.contains(&(Position::new(9, 15), "= 0x1020".to_string())));
+    // Pasted tokens are evaluated the same way as they are expanded
+    assert!(hints.contains(&(Position::new(11, 21), "= 0x40".to_string())));
+}
+
+#[tokio::test]
+async fn inactive_0() {
+    let be = &make_backend("tests/inactive/").await;
+    let path = "board.dts";
+    be.mock_open(path).await;
+
+    // Undefined macro in skipped branch is not reported
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((9, 0), (10, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+            (
+                make_range((13, 0), (14, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+            (
+                make_range((19, 0), (22, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+        ]
+    );
+    assert_eq!(be.diagnostics("config.h"), vec![]);
+}
+
+#[tokio::test]
+async fn predefined_0() {
+    let be = &make_backend("tests/predefined/").await;
+    let path = "board.dts";
+    let strings = |x: &[&str]| x.iter().map(ToString::to_string).collect::<Vec<_>>();
+    be.data.id.set_predefined(
+        &strings(&[
+            "CLOCK_RATE=24000000",
+            "SCALE(x)=((x) * 100)",
+            "BOARD_REV=1",
+            "-DCONFIG_DEBUG",
+        ]),
+        &[],
+    );
+    be.data.id.set_board_predefined(
+        &be.make_url(path),
+        &strings(&["BOARD_REV=2"]),
+        &strings(&["CONFIG_DEBUG"]),
+    );
+    be.mock_open(path).await;
+
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((12, 0), (14, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+            (
+                make_range((17, 0), (19, 0)),
+                "Inactive preprocessor block".to_string()
+            ),
+        ]
+    );
+    assert_eq!(
+        be.mock_hover(path, Position::new(5, 3)).await.unwrap(),
+        "**rate**: 32-bit cells\n\n- `<SCALE(2)>` = `<0xc8>`"
+    );
+    assert!(be
+        .mock_goto_definition(path, Position::new(4, 22))
+        .await
+        .unwrap()
+        .is_none());
+}
+
+#[tokio::test]
+async fn predefined_1() {
+    let be = &make_backend("tests/predefined_boards/").await;
+    let strings = |x: &[&str]| x.iter().map(ToString::to_string).collect::<Vec<_>>();
+    be.data
+        .id
+        .set_board_predefined(&be.make_url("board_a.dts"), &strings(&["BOARD_REV=1"]), &[]);
+    be.data
+        .id
+        .set_board_predefined(&be.make_url("board_b.dts"), &strings(&["BOARD_REV=2"]), &[]);
+    be.mock_open("board_a.dts").await;
+    be.mock_open("board_b.dts").await;
+
+    // Each board sees only its own settings
+    assert_eq!(
+        be.diagnostics("board_a.dts"),
+        vec![(
+            make_range((6, 0), (7, 0)),
+            "Inactive preprocessor block".to_string()
+        )]
+    );
+    assert_eq!(be.diagnostics("board_b.dts"), vec![]);
+    assert_eq!(
+        be.data.id.predefined_flags(&be.make_url("board_b.dts")),
+        vec!["-DBOARD_REV=2"]
+    );
+
+    // Shared file always uses settings of the same board
+    assert_eq!(
+        be.diagnostics("soc.dtsi"),
+        vec![(
+            make_range((2, 0), (4, 0)),
+            "Inactive preprocessor block".to_string()
+        )]
+    );
+    assert_eq!(
+        be.data.id.predefined_flags(&be.make_url("soc.dtsi")),
+        vec!["-DBOARD_REV=1"]
+    );
+}
+
+#[tokio::test]
+async fn expand_macros_0() {
+    let be = &make_backend("tests/expansion/").await;
+    let path = "board.dts";
+    be.mock_open(path).await;
+
+    let expand = |line, character| async move {
+        let position = Position::new(line, character);
+        be.mock_expand_macros(path, Range::new(position, position))
+            .await
+            .unwrap()
+    };
+    assert_eq!(
+        expand(7, 10).await,
+        "pinmux = <((((0) << 8) | (5)) | ((3) << 16))>, /* PORT_A */\n\
+        \t\t\t <((((0) << 8) | (6)) | ((3) << 16))>;"
+    );
+    assert_eq!(expand(8, 10).await, "label = \"uart0\";");
+    assert_eq!(expand(9, 10).await, "reg = <0x4000 (SELF + 1)>;");
+    assert_eq!(expand(4, 0).await, "/ {");
+
+    // Explicit range, macro that is cut off is not expanded
+    let range = make_range((7, 5), (7, 20));
+    assert_eq!(
+        be.mock_expand_macros(path, range).await.unwrap(),
+        "PINMUX(0, "
+    );
+}
+
+#[tokio::test]
+async fn dtc_0() {
+    // dtc is replaced with a script, but real preprocessor is needed for line markers
+    if std::process::Command::new("cpp")
+        .arg("--version")
+        .output()
+        .is_err()
+    {
+        eprintln!("cpp is not installed, skipping");
+        return;
+    }
+
+    let be = &make_backend("tests/dtc/").await;
+    let path = "board.dts";
+    let dtc = be.make_url("fake-dtc").to_file_path().unwrap();
+    be.data
+        .cc
+        .configure(Some(dtc.to_string_lossy().to_string()), None, Vec::new());
+    be.mock_open(path).await;
+
+    be.did_save(DidSaveTextDocumentParams {
+        text_document: TextDocumentIdentifier::new(be.make_url("soc.dtsi")),
+        text: None,
+    })
+    .await;
+
+    // Positions in preprocessed input are mapped back to the files
+    assert_eq!(
+        be.diagnostics(path),
+        vec![
+            (
+                make_range((0, 0), (0, 0)),
+                "Unable to parse input tree".to_string()
+            ),
+            (make_range((7, 11), (7, 12)), "syntax error".to_string()),
+        ]
+    );
+    assert_eq!(
+        be.diagnostics("soc.dtsi"),
+        vec![(
+            make_range((7, 2), (7, 22)),
+            "Warning (reg_format): /soc/serial@1000:reg: property has invalid length".to_string()
+        )]
+    );
+}
+
+#[tokio::test]
+async fn dtb_0() {
+    let be = &make_backend("tests/dtb/").await;
+    let path = "board.dtb";
+    let url = be.make_url(path);
+
+    let text = be
+        .decompile(TextDocumentIdentifier::new(url.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        text,
+        "/dts-v1/;\n\
+        \n\
+        /memreserve/ 0x80000000 0x10000;\n\
+        \n\
+        / {\n\
+        \t#address-cells = <0x1>;\n\
+        \t#size-cells = <0x1>;\n\
+        \tcompatible = \"vendor,board\", \"vendor,soc\";\n\
+        \tmodel = \"Vendor \\\"Board\\\"\";\n\
+        \n\
+        \tuart0: serial@1000 {\n\
+        \t\tcompatible = \"vendor,uart\";\n\
+        \t\treg = <0x1000 0x100>;\n\
+        \t\tstatus = \"okay\";\n\
+        \t\tmac = [00 11 22 33 44 55];\n\
+        \t\tdma-coherent;\n\
+        \t\tphandle = <0x1>;\n\
+        \t};\n\
+        \n\
+        \tleds {\n\
+        \t\tcompatible = \"gpio-leds\";\n\
+        \t\tclocks = <0x1 0x2>;\n\
+        \t};\n\
+        \n\
+        \t__symbols__ {\n\
+        \t\tuart0 = \"/serial@1000\";\n\
+        \t};\n\
+        };\n"
+    );
+
+    // Decompiled source is handled like any other top-level file
+    assert_eq!(
+        be.mock_hover(path, Position::new(12, 3)).await.unwrap(),
+        "**reg**\n\nCPU addresses:\n- `<0x1000 0x100>` → `0x1000-0x10ff`"
+    );
+    assert!(be.has_label(path, "uart0") > 0);
+
+    // Sources are not decompiled
+    let source = TextDocumentIdentifier::new(be.make_url("missing.dts"));
+    assert_eq!(be.decompile(source).await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn dtb_1() {
+    let be = &make_backend("tests/dtb/").await;
+    let path = "overlay.dtbo";
+    let url = be.make_url(path);
+    be.data.fd.set_overlay_base("board.dtb");
+
+    // Placeholders of external references are turned back into labels
+    let text = be
+        .decompile(TextDocumentIdentifier::new(url.clone()))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(
+        text,
+        "/dts-v1/;\n\
+        /plugin/;\n\
+        \n\
+        / {\n\
+        \n\
+        \tfragment@0 {\n\
+        \t\ttarget = <&uart0>;\n\
+        \n\
+        \t\t__overlay__ {\n\
+        \t\t\tstatus = \"okay\";\n\
+        \t\t};\n\
+        \t};\n\
+        };\n"
+    );
+
+    // Decompiled overlay is applied to the base tree
+    let trees = be.data.device_trees(&url);
+    assert_eq!(trees.len(), 1);
+    let idx = trees[0].find_path("/serial@1000").unwrap();
+    let status = trees[0].node(idx).property("status").unwrap();
+    assert_eq!(status.strings(), vec!["okay"]);
+    assert!(trees[0].find_path("/fragment@0").is_none());
+}
+
+#[tokio::test]
+async fn compare_blob_0() {
+    let be = &make_backend("tests/compare/").await;
+
+    // Macros and references are compared by their values, generated phandles and symbols
+    // are ignored
+    assert_eq!(
+        be.mock_compare_blob("board.dts", "board.dtb")
+            .await
+            .unwrap(),
+        vec![
+            "/serial@1000:current-speed: Property is only in the blob (soc.dtsi:9) <0x1c200>",
+            "/memory@80000000:reg: Value differs from the blob (board.dts:12) \
+            <0x80000000 0x10000000> -> <0x80000000 0x20000000>",
+            "/leds: Node is missing in the blob (board.dts:19)",
+            "/chosen: Node is only in the blob (soc.dtsi:0)",
+        ]
+    );
+
+    assert_eq!(be.mock_compare_blob("board.dts", "soc.dtsi").await, None);
+}
@@ -0,0 +1,141 @@
+use crate::addresses;
+use crate::device_tree::{CellKind, DeviceTree, Property, Value};
+use crate::includes_depot::IncludesDepot;
+use crate::interrupts;
+use tower_lsp::lsp_types::{Position, Url};
+
+// Markdown description of the property at the position, based on the merged tree
+pub fn hover(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    uri: &Url,
+    position: Position,
+) -> Option<String> {
+    let (idx, property) = tree.property_at(uri, position)?;
+    match property.name.as_str() {
+        "interrupts" | "interrupts-extended" => hover_interrupts(tree, id, idx, property),
+        "reg" => hover_reg(tree, id, idx, property),
+        _ => Some(hover_value(tree, id, property)),
+    }
+}
+
+fn value_type(values: &[Value]) -> String {
+    match values {
+        [] => String::from("empty"),
+        [Value::String(_)] => String::from("string"),
+        [Value::Cells(bits, _)] => format!("{bits}-bit cells"),
+        [Value::Bytes(_)] => String::from("byte string"),
+        [Value::Path(_)] => String::from("path"),
+        [Value::Other] => String::from("malformed"),
+        _ if values.iter().all(|x| matches!(x, Value::String(_))) => String::from("string list"),
+        _ => String::from("mixed"),
+    }
+}
+
+// Decoded values with cells evaluated
+fn hover_value(tree: &DeviceTree, id: &IncludesDepot, property: &Property) -> String {
+    let mut lines = Vec::new();
+    for value in &property.values {
+        lines.push(match value {
+            Value::String(x) => format!("- `\"{x}\"`"),
+            Value::Cells(bits, cells) => {
+                let source: Vec<_> = cells.iter().map(|x| x.text.as_str()).collect();
+                let source = source.join(" ");
+                let values: Vec<_> = cells
+                    .iter()
+                    .map(|x| match (&x.kind, x.value(&property.uri, id)) {
+                        (CellKind::Expression, Some(v)) if *bits < 64 => {
+                            format!("{:#x}", v & ((1u64 << bits) - 1))
+                        }
+                        (CellKind::Expression, Some(v)) => format!("{v:#x}"),
+                        _ => x.text.clone(),
+                    })
+                    .collect();
+                let values = values.join(" ");
+                if values == source {
+                    format!("- `<{source}>`")
+                } else {
+                    format!("- `<{source}>` = `<{values}>`")
+                }
+            }
+            Value::Bytes(x) => {
+                let bytes: Vec<_> = x.iter().map(|x| format!("{x:02x}")).collect();
+                format!("- `[{}]` ({} bytes)", bytes.join(" "), x.len())
+            }
+            Value::Path(x) => match tree.resolve(x) {
+                Some(idx) => format!("- `{x}` → `\"{}\"`", tree.path(idx)),
+                None => format!("- `{x}`: unresolved"),
+            },
+            Value::Other => continue,
+        });
+    }
+
+    let header = format!("**{}**: {}", property.name, value_type(&property.values));
+    if lines.is_empty() {
+        header
+    } else {
+        format!("{header}\n\n{}", lines.join("\n"))
+    }
+}
+
+fn hover_interrupts(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    idx: usize,
+    property: &Property,
+) -> Option<String> {
+    let specifiers = interrupts::specifiers(tree, id, idx, property)?;
+    let mut res = vec![format!("**{}**", property.name)];
+
+    if property.name == "interrupts" {
+        let parent = interrupts::interrupt_parent(tree, idx)?;
+        res.push(format!("Interrupt parent: `{}`", tree.path(parent)));
+    }
+
+    let mut lines = Vec::new();
+    for specifier in specifiers {
+        let cells: Vec<_> = specifier.cells.iter().map(|x| x.text.as_str()).collect();
+        let cells = cells.join(" ");
+        let Some(interrupt) = specifier.interrupt else {
+            lines.push(format!("- `<{cells}>`: no interrupt controller"));
+            continue;
+        };
+
+        let via: Vec<_> = interrupt
+            .via
+            .iter()
+            .map(|x| format!(" via `{}`", tree.path(*x)))
+            .collect();
+        let via = via.concat();
+        lines.push(format!(
+            "- `<{cells}>` → `{}`: {}{via}",
+            tree.path(interrupt.controller),
+            interrupts::describe(tree, &interrupt)
+        ));
+    }
+    res.push(lines.join("\n"));
+
+    Some(res.join("\n\n"))
+}
+
+fn hover_reg(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    idx: usize,
+    property: &Property,
+) -> Option<String> {
+    let regions = addresses::regions(tree, id, idx, property)?;
+    let lines: Vec<_> = regions
+        .iter()
+        .map(|x| {
+            let cells: Vec<_> = x.cells.iter().map(|x| x.text.as_str()).collect();
+            let cells = cells.join(" ");
+            match x.describe() {
+                Some(range) => format!("- `<{cells}>` → `{range}`"),
+                None => format!("- `<{cells}>`: not mapped to CPU address space"),
+            }
+        })
+        .collect();
+
+    Some(format!("**reg**\n\nCPU addresses:\n{}", lines.join("\n")))
+}
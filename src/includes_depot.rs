@@ -1,4 +1,7 @@
 use crate::file_depot::FileDepot;
+use crate::macros::parse_define;
+use crate::macros::Macro;
+use crate::utils::is_header;
 use crate::utils::Symbol;
 use std::collections::HashMap;
 use std::collections::HashSet;
@@ -16,8 +19,42 @@ struct Define {
     name: String,
 }
 
+struct Definition {
+    range: Range,
+    value: String,
+    // Parameters of function-like macro
+    params: Option<Vec<String>>,
+}
+
+// Macros defined and undefined outside of source files, like -D and -U options of cpp
+#[derive(Default)]
+struct Predefined {
+    // Undefined macros have no definition
+    macros: HashMap<String, Option<Definition>>,
+}
+
+impl Predefined {
+    fn new(defines: &[String], undefines: &[String]) -> Predefined {
+        let mut macros = HashMap::new();
+        for (name, params, value) in defines.iter().filter_map(|x| parse_define(x)) {
+            let definition = Definition {
+                range: Range::default(),
+                value,
+                params,
+            };
+            macros.insert(name, Some(definition));
+        }
+        for name in undefines {
+            macros.insert(name.trim().to_string(), None);
+        }
+        Predefined { macros }
+    }
+}
+
 struct Data {
-    define_to_symbol: HashMap<Define, (Range, String)>,
+    define_to_symbol: HashMap<Define, Definition>,
+    project: Predefined,
+    boards: HashMap<Url, Predefined>,
     fd: FileDepot,
 }
 
@@ -25,21 +62,34 @@ impl Data {
     fn new(fd: &FileDepot) -> Data {
         Data {
             define_to_symbol: HashMap::new(),
+            project: Predefined::default(),
+            boards: HashMap::new(),
             fd: fd.clone(),
         }
     }
 
-    fn add_define(&mut self, name: &str, uri: &Url, range: Range, value: &str) {
+    fn add_define(
+        &mut self,
+        name: &str,
+        uri: &Url,
+        range: Range,
+        value: &str,
+        params: Option<Vec<String>>,
+    ) {
         self.define_to_symbol.insert(
             Define {
                 uri: uri.clone(),
                 name: name.to_string(),
             },
-            (range, value.to_string()),
+            Definition {
+                range,
+                value: value.to_string(),
+                params,
+            },
         );
     }
 
-    fn find_define(&self, uri: &Url, name: &str) -> Option<Symbol> {
+    fn lookup(&self, uri: &Url, name: &str) -> Option<(Url, &Definition)> {
         let mut visited = HashSet::new();
         let mut to_visit = vec![uri.clone()];
 
@@ -55,7 +105,7 @@ impl Data {
                 name: name.to_string(),
                 uri: uri.clone(),
             }) {
-                return Some(Symbol::new(uri.clone(), x.0));
+                return Some((uri, x));
             }
             visited.insert(uri);
         }
@@ -63,6 +113,89 @@ impl Data {
         None
     }
 
+    // Board settings of the file itself or of the first of its top-level files that has them
+    fn board(&self, uri: &Url) -> Option<&Predefined> {
+        if let Some(x) = self.boards.get(uri) {
+            return Some(x);
+        }
+        let mut roots = self.fd.get_roots(uri);
+        roots.sort();
+        roots.iter().find_map(|x| self.boards.get(x))
+    }
+
+    // Predefined macro, board settings take precedence over project ones
+    fn predefined(&self, uri: &Url, name: &str) -> Option<&Definition> {
+        let board = self.board(uri).and_then(|x| x.macros.get(name));
+        board.or_else(|| self.project.macros.get(name))?.as_ref()
+    }
+
+    // Predefined macros as -D and -U options, board settings go last to override project ones
+    fn predefined_flags(&self, uri: &Url) -> Vec<String> {
+        let board = self.board(uri);
+
+        let mut res = Vec::new();
+        for x in std::iter::once(&self.project).chain(board) {
+            let mut flags: Vec<_> = x
+                .macros
+                .iter()
+                .map(|(name, x)| match x {
+                    Some(Definition {
+                        value,
+                        params: Some(params),
+                        ..
+                    }) => format!("-D{name}({})={value}", params.join(",")),
+                    Some(Definition { value, .. }) => format!("-D{name}={value}"),
+                    None => format!("-U{name}"),
+                })
+                .collect();
+            flags.sort();
+            res.append(&mut flags);
+        }
+        res
+    }
+
+    // Definitions in source files take precedence over predefined macros
+    fn definition(&self, uri: &Url, name: &str) -> Option<&Definition> {
+        self.lookup(uri, name)
+            .map(|(_, x)| x)
+            .or_else(|| self.predefined(uri, name))
+    }
+
+    fn find_define(&self, uri: &Url, name: &str) -> Option<Symbol> {
+        self.lookup(uri, name)
+            .map(|(uri, x)| Symbol::new(uri.clone(), x.range))
+    }
+
+    fn is_defined(&self, uri: &Url, name: &str) -> bool {
+        self.definition(uri, name).is_some()
+    }
+
+    fn find_value(&self, uri: &Url, name: &str) -> Option<String> {
+        self.definition(uri, name).map(|x| x.value.clone())
+    }
+
+    fn find_params(&self, uri: &Url, name: &str) -> Option<Vec<String>> {
+        self.definition(uri, name).and_then(|x| x.params.clone())
+    }
+
+    fn find_macro(&self, uri: &Url, name: &str) -> Option<Macro> {
+        self.definition(uri, name).map(|x| Macro {
+            value: x.value.clone(),
+            params: x.params.clone(),
+        })
+    }
+
+    fn find_headers(&self, name: &str) -> Vec<Url> {
+        let mut res: Vec<Url> = self
+            .define_to_symbol
+            .keys()
+            .filter(|x| x.name == name && is_header(&x.uri))
+            .map(|x| x.uri.clone())
+            .collect();
+        res.sort();
+        res
+    }
+
     fn invalidate(&mut self, uri: &Url) {
         let mut v = Vec::new();
 
@@ -81,7 +214,7 @@ impl Data {
     fn dump(&self) {
         info!("====== (defines) ======");
         for (k, v) in &self.define_to_symbol {
-            info!("url: {}: {}", k.uri, v.1);
+            info!("url: {}: {}", k.uri, v.value);
         }
         info!("======================");
     }
@@ -99,17 +232,64 @@ impl IncludesDepot {
         }
     }
 
-    pub fn add_define(&self, name: &str, uri: &Url, range: Range, value: &str) {
+    pub fn add_define(
+        &self,
+        name: &str,
+        uri: &Url,
+        range: Range,
+        value: &str,
+        params: Option<Vec<String>>,
+    ) {
         self.data
             .lock()
             .unwrap()
-            .add_define(name, uri, range, value);
+            .add_define(name, uri, range, value, params);
+    }
+
+    pub fn set_predefined(&self, defines: &[String], undefines: &[String]) {
+        self.data.lock().unwrap().project = Predefined::new(defines, undefines);
     }
 
+    pub fn set_board_predefined(&self, uri: &Url, defines: &[String], undefines: &[String]) {
+        let predefined = Predefined::new(defines, undefines);
+        self.data
+            .lock()
+            .unwrap()
+            .boards
+            .insert(uri.clone(), predefined);
+    }
+
+    pub fn predefined_flags(&self, uri: &Url) -> Vec<String> {
+        self.data.lock().unwrap().predefined_flags(uri)
+    }
+
+    // Location of the definition in source files, predefined macros don't have one
     pub fn find_define(&self, uri: &Url, name: &str) -> Option<Symbol> {
         self.data.lock().unwrap().find_define(uri, name)
     }
 
+    // Whether macro is defined either in source files or in settings
+    pub fn is_defined(&self, uri: &Url, name: &str) -> bool {
+        self.data.lock().unwrap().is_defined(uri, name)
+    }
+
+    pub fn find_value(&self, uri: &Url, name: &str) -> Option<String> {
+        self.data.lock().unwrap().find_value(uri, name)
+    }
+
+    pub fn find_params(&self, uri: &Url, name: &str) -> Option<Vec<String>> {
+        self.data.lock().unwrap().find_params(uri, name)
+    }
+
+    pub fn find_macro(&self, uri: &Url, name: &str) -> Option<Macro> {
+        self.data.lock().unwrap().find_macro(uri, name)
+    }
+
+    // Headers that define the macro, regardless of whether they are included anywhere
+    pub fn find_headers(&self, name: &str) -> Vec<Url> {
+        self.data.lock().unwrap().find_headers(name)
+    }
+
     #[cfg(test)]
     pub fn dump(&self) {
         self.data.lock().unwrap().dump();
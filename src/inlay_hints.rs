@@ -0,0 +1,219 @@
+use crate::addresses;
+use crate::device_tree::{CellKind, DeviceTree, Property};
+use crate::diagnostics::{is_macro_use, walk};
+use crate::encoding::{convert_range, offset_position};
+use crate::expressions;
+use crate::includes_depot::IncludesDepot;
+use crate::interrupts;
+use crate::macros::is_identifier;
+use crate::macros::split_arguments;
+use crate::utils::parse_integer;
+use crate::validation::phandle_cells_name;
+use tower_lsp::lsp_types::{Position, Url};
+use tree_sitter::Tree;
+
+/*
+ * Hints are returned as (position, label) pairs. Labels ending with ':' name the
+ * following cell, other labels describe the preceding one.
+ */
+
+// Names of arguments for common bindings, by name of #*-cells property
+const ARGUMENT_NAMES: &[(&str, &[&str])] = &[
+    ("#gpio-cells", &["pin", "flags"]),
+    ("#interrupt-cells", &["irq"]),
+    ("#interrupt-cells", &["irq", "flags"]),
+    ("#interrupt-cells", &["type", "irq", "flags"]),
+    ("#pwm-cells", &["channel", "period"]),
+    ("#pwm-cells", &["channel", "period", "flags"]),
+    ("#dma-cells", &["request"]),
+    ("#phy-cells", &["mode"]),
+    ("#mbox-cells", &["channel"]),
+    ("#io-channel-cells", &["channel"]),
+    ("#iommu-cells", &["stream"]),
+];
+
+// Longer macro values are not useful as hints
+const MAX_VALUE_LENGTH: usize = 32;
+
+fn argument_names(cells_name: &str, n: usize) -> Vec<String> {
+    if let Some((_, names)) = ARGUMENT_NAMES
+        .iter()
+        .find(|(name, names)| *name == cells_name && names.len() == n)
+    {
+        return names.iter().map(ToString::to_string).collect();
+    }
+
+    // Single argument is named after the cells property, e.g. #clock-cells
+    match cells_name
+        .strip_prefix('#')
+        .and_then(|x| x.strip_suffix("-cells"))
+    {
+        Some(x) if n == 1 => vec![x.to_string()],
+        _ => Vec::new(),
+    }
+}
+
+// Names of phandle arguments, e.g. <&gpio pin: 5 flags: 0>
+fn argument_hints(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    idx: usize,
+    property: &Property,
+) -> Vec<(Position, String)> {
+    let mut res = Vec::new();
+    let Some(cells) = property.cells() else {
+        return res;
+    };
+
+    if property.name == "interrupts" {
+        let Some(n) = interrupts::interrupt_parent(tree, idx)
+            .and_then(|x| tree.cell_count(x, "#interrupt-cells", id))
+        else {
+            return res;
+        };
+        let names = argument_names("#interrupt-cells", n);
+        for group in cells.chunks(n.max(1)) {
+            for (cell, name) in group.iter().zip(&names) {
+                res.push((cell.range.start, format!("{name}:")));
+            }
+        }
+        return res;
+    }
+
+    let Some((cells_name, optional)) = phandle_cells_name(&property.name) else {
+        return res;
+    };
+    let mut i = 0;
+    while i < cells.len() {
+        let CellKind::Reference(reference) = &cells[i].kind else {
+            i += 1;
+            continue;
+        };
+        let Some(target) = tree.resolve(reference) else {
+            break;
+        };
+        let n = match tree.node(target).property(cells_name) {
+            Some(_) => tree.cell_count(target, cells_name, id),
+            None if optional => Some(0),
+            None => None,
+        };
+        let Some(n) = n else {
+            break;
+        };
+
+        let args = cells[i + 1..].iter().take(n);
+        for (cell, name) in args.zip(argument_names(cells_name, n)) {
+            res.push((cell.range.start, format!("{name}:")));
+        }
+        i += n + 1;
+    }
+    res
+}
+
+// Hints for the file based on the merged tree
+pub fn inlay_hints(tree: &DeviceTree, id: &IncludesDepot, uri: &Url) -> Vec<(Position, String)> {
+    let mut res = Vec::new();
+    for idx in tree.nodes() {
+        let node = tree.node(idx);
+        for property in node.properties.iter().filter(|x| x.uri == *uri) {
+            res.append(&mut argument_hints(tree, id, idx, property));
+        }
+
+        // Addresses of root children are CPU addresses already
+        if matches!(node.parent, None | Some(DeviceTree::ROOT)) {
+            continue;
+        }
+
+        let Some(reg) = node.property("reg").filter(|x| x.uri == *uri) else {
+            continue;
+        };
+        for region in addresses::regions(tree, id, idx, reg).unwrap_or_default() {
+            if let (Some(cpu), Some(cell)) = (region.cpu, region.cells.last()) {
+                res.push((cell.range.end, format!("→ {cpu:#x}")));
+            }
+        }
+    }
+    res
+}
+
+// Value of macro, following macros that are defined as other macros
+fn macro_value(uri: &Url, id: &IncludesDepot, name: &str) -> Option<String> {
+    let mut value = name.to_string();
+    // Limit depth to avoid looping on recursive definitions
+    for _ in 0..16 {
+        if id.find_params(uri, &value).is_some() {
+            return None;
+        }
+        let next = id.find_value(uri, &value)?;
+        if parse_integer(&next).is_some() {
+            return Some(next);
+        }
+        // Expressions are shown evaluated
+        if !is_identifier(&next) {
+            return match expressions::evaluate(&next, &|x| id.find_macro(uri, x)) {
+                Ok(x) => Some(format!("{x:#x}")),
+                Err(_) if next.is_empty() || next.len() > MAX_VALUE_LENGTH => None,
+                Err(_) => Some(next),
+            };
+        }
+        value = next;
+    }
+    None
+}
+
+// Values of macros and names of macro parameters, based on the file syntax tree
+pub fn macro_hints(
+    tree: &Tree,
+    text: &str,
+    uri: &Url,
+    id: &IncludesDepot,
+) -> Vec<(Position, String)> {
+    let mut res = Vec::new();
+    walk(tree.root_node(), &mut |node| {
+        if !is_macro_use(&node) {
+            return;
+        }
+        let name = node.utf8_text(text.as_bytes()).unwrap();
+        let range = convert_range(&node.range());
+
+        let call = node
+            .parent()
+            .filter(|x| x.kind() == "call_expression")
+            .and_then(|x| x.child_by_field_name("arguments"));
+        let Some(arguments) = call else {
+            if let Some(value) = macro_value(uri, id, name) {
+                res.push((range.end, format!("= {value}")));
+            }
+            return;
+        };
+
+        let start = convert_range(&arguments.range()).start;
+        let arguments_text = arguments.utf8_text(text.as_bytes()).unwrap();
+
+        let call = node.parent().unwrap();
+        let call_text = call.utf8_text(text.as_bytes()).unwrap();
+        if let Ok(value) = expressions::evaluate(call_text, &|x| id.find_macro(uri, x)) {
+            let end = convert_range(&call.range()).end;
+            res.push((end, format!("= {value:#x}")));
+        }
+        let params = id.find_params(uri, name).unwrap_or_default();
+        for (i, (offset, argument)) in split_arguments(arguments_text).into_iter().enumerate() {
+            if let Some(param) = params.get(i) {
+                let position = offset_position(start, arguments_text, offset);
+                res.push((position, format!("{param}:")));
+            }
+
+            let value = is_identifier(argument)
+                .then(|| macro_value(uri, id, argument))
+                .flatten();
+            if let Some(value) = value {
+                let end = offset + argument.len();
+                res.push((
+                    offset_position(start, arguments_text, end),
+                    format!("= {value}"),
+                ));
+            }
+        }
+    });
+    res
+}
@@ -0,0 +1,327 @@
+use crate::device_tree::{Cell, CellKind, DeviceTree, Property};
+use crate::includes_depot::IncludesDepot;
+use crate::utils::range_contains;
+use tower_lsp::lsp_types::{Position, Url};
+
+/*
+ * Interrupt tree as described in devicetree specification: interrupt parent is either
+ * set explicitly or inherited from the tree parent, interrupt nexus nodes translate
+ * specifiers through interrupt-map until an interrupt-controller is reached.
+ */
+
+// Limit on the number of hops to avoid looping on broken trees
+const MAX_DEPTH: usize = 32;
+
+// Cell values, None for cells that could not be evaluated
+type Values = Vec<Option<u64>>;
+
+pub struct Interrupt {
+    pub controller: usize,
+    // Specifier as seen by controller
+    pub specifier: Values,
+    // Nexus nodes the interrupt was routed through
+    pub via: Vec<usize>,
+}
+
+pub struct Specifier<'a> {
+    pub cells: Vec<&'a Cell>,
+    pub interrupt: Option<Interrupt>,
+}
+
+enum Item {
+    Number(Option<u64>),
+    Node(Option<usize>),
+}
+
+fn items(tree: &DeviceTree, id: &IncludesDepot, property: &Property) -> Option<Vec<Item>> {
+    let items = property
+        .cells()?
+        .into_iter()
+        .map(|x| match &x.kind {
+            CellKind::Reference(r) => Item::Node(tree.resolve(r)),
+            CellKind::Expression => Item::Number(x.value(&property.uri, id)),
+        })
+        .collect();
+    Some(items)
+}
+
+fn numbers(items: &[Item]) -> Option<Values> {
+    items
+        .iter()
+        .map(|x| match x {
+            Item::Number(x) => Some(*x),
+            Item::Node(_) => None,
+        })
+        .collect()
+}
+
+// Node referenced by the first cell of a property, e.g. interrupt-parent = <&gic>
+fn phandle(tree: &DeviceTree, property: &Property) -> Option<usize> {
+    match &property.cells()?.first()?.kind {
+        CellKind::Reference(x) => tree.resolve(x),
+        CellKind::Expression => None,
+    }
+}
+
+// Effective interrupt parent of the node, mirrors of_irq_find_parent() from Linux
+pub fn interrupt_parent(tree: &DeviceTree, idx: usize) -> Option<usize> {
+    let mut cur = idx;
+    for _ in 0..MAX_DEPTH {
+        let node = tree.node(cur);
+        cur = match node.property("interrupt-parent") {
+            Some(x) => phandle(tree, x)?,
+            None => node.parent?,
+        };
+        if tree.node(cur).property("#interrupt-cells").is_some() {
+            return Some(cur);
+        }
+    }
+    None
+}
+
+// Child unit address used for interrupt-map lookup, taken from the first reg entry
+fn unit_address(tree: &DeviceTree, id: &IncludesDepot, idx: usize) -> Values {
+    let node = tree.node(idx);
+    let Ok(n) = usize::try_from(tree.address_cells(node.parent, id)) else {
+        return Vec::new();
+    };
+    let Some(reg) = node.property("reg") else {
+        return vec![None; n];
+    };
+
+    let mut res: Vec<_> = reg
+        .cells()
+        .unwrap_or_default()
+        .iter()
+        .take(n)
+        .map(|x| x.value(&reg.uri, id))
+        .collect();
+    res.resize(n, None);
+    res
+}
+
+// Find interrupt-map entry matching child address and specifier
+fn map_lookup(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    nexus: usize,
+    address: &[Option<u64>],
+    specifier: &[Option<u64>],
+) -> Option<(usize, Values, Values)> {
+    let node = tree.node(nexus);
+    let map = items(tree, id, node.property("interrupt-map")?)?;
+    let address_cells = tree.cell_count(nexus, "#address-cells", id).unwrap_or(0);
+    let interrupt_cells = tree.cell_count(nexus, "#interrupt-cells", id)?;
+
+    let mut child = address.to_vec();
+    child.resize(address_cells, Some(0));
+    child.extend_from_slice(specifier);
+    child.resize(address_cells + interrupt_cells, None);
+    let child = child.into_iter().collect::<Option<Vec<u64>>>()?;
+
+    let mask = match node.property("interrupt-map-mask") {
+        Some(x) => numbers(&items(tree, id, x)?)?,
+        None => Vec::new(),
+    };
+    let masked: Vec<_> = child
+        .iter()
+        .enumerate()
+        .map(|(i, x)| x & mask.get(i).copied().flatten().unwrap_or(u64::MAX))
+        .collect();
+
+    let mut i = 0;
+    while i + child.len() < map.len() {
+        let entry = numbers(&map[i..i + child.len()])?;
+        let Item::Node(Some(parent)) = map[i + child.len()] else {
+            return None;
+        };
+        i += child.len() + 1;
+
+        let parent_address_cells = tree.cell_count(parent, "#address-cells", id).unwrap_or(0);
+        let parent_interrupt_cells = tree.cell_count(parent, "#interrupt-cells", id)?;
+        let end = i + parent_address_cells + parent_interrupt_cells;
+        if end > map.len() {
+            return None;
+        }
+        let parent_address = numbers(&map[i..i + parent_address_cells])?;
+        let parent_specifier = numbers(&map[i + parent_address_cells..end])?;
+        i = end;
+
+        if entry.iter().zip(&masked).all(|(a, b)| *a == Some(*b)) {
+            return Some((parent, parent_address, parent_specifier));
+        }
+    }
+    None
+}
+
+// Route the specifier from interrupt parent up to the interrupt controller
+fn route(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    mut parent: usize,
+    mut address: Values,
+    mut specifier: Values,
+) -> Option<Interrupt> {
+    let mut via = Vec::new();
+    for _ in 0..MAX_DEPTH {
+        let node = tree.node(parent);
+        if node.property("interrupt-controller").is_some() {
+            return Some(Interrupt {
+                controller: parent,
+                specifier,
+                via,
+            });
+        }
+
+        if node.property("interrupt-map").is_some() {
+            via.push(parent);
+            (parent, address, specifier) = map_lookup(tree, id, parent, &address, &specifier)?;
+        } else {
+            // Neither controller nor nexus, interrupt is passed further up unchanged
+            parent = interrupt_parent(tree, parent)?;
+        }
+    }
+    None
+}
+
+/*
+ * Split interrupts or interrupts-extended property of the node into specifiers and
+ * resolve each of them. None is returned when the interrupt parent can't be found.
+ */
+pub fn specifiers<'a>(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    idx: usize,
+    property: &'a Property,
+) -> Option<Vec<Specifier<'a>>> {
+    let cells = property.cells()?;
+    let address = unit_address(tree, id, idx);
+    let mut res = Vec::new();
+
+    if property.name == "interrupts-extended" {
+        let mut i = 0;
+        while i < cells.len() {
+            let CellKind::Reference(reference) = &cells[i].kind else {
+                return None;
+            };
+            let parent = tree.resolve(reference)?;
+            let n = tree.cell_count(parent, "#interrupt-cells", id)?;
+            let group = cells.get(i..=i + n)?;
+            res.push(specifier(
+                tree,
+                id,
+                &property.uri,
+                parent,
+                &address,
+                group,
+                &group[1..],
+            ));
+            i += n + 1;
+        }
+        return Some(res);
+    }
+
+    let parent = interrupt_parent(tree, idx)?;
+    let n = tree.cell_count(parent, "#interrupt-cells", id)?;
+    for group in cells.chunks(n.max(1)) {
+        res.push(specifier(
+            tree,
+            id,
+            &property.uri,
+            parent,
+            &address,
+            group,
+            group,
+        ));
+    }
+    Some(res)
+}
+
+fn specifier<'a>(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    uri: &Url,
+    parent: usize,
+    address: &[Option<u64>],
+    cells: &[&'a Cell],
+    args: &[&'a Cell],
+) -> Specifier<'a> {
+    let values = args
+        .iter()
+        .map(|x| match x.kind {
+            CellKind::Expression => x.value(uri, id),
+            CellKind::Reference(_) => None,
+        })
+        .collect();
+
+    Specifier {
+        cells: cells.to_vec(),
+        interrupt: route(tree, id, parent, address.to_vec(), values),
+    }
+}
+
+fn trigger(flags: u64) -> &'static str {
+    match flags & 0xf {
+        0 => "none",
+        1 => "edge rising",
+        2 => "edge falling",
+        3 => "edge both",
+        4 => "level high",
+        8 => "level low",
+        _ => "invalid trigger",
+    }
+}
+
+// Decode the specifier for common bindings, e.g. <GIC_SPI 5 IRQ_TYPE_LEVEL_HIGH>
+pub fn describe(tree: &DeviceTree, interrupt: &Interrupt) -> String {
+    let node = tree.node(interrupt.controller);
+    let gic = node
+        .property("compatible")
+        .is_some_and(|x| x.strings().iter().any(|x| x.contains("gic")));
+
+    // Cells are stored as 32-bit values, negative numbers are not masked when evaluated
+    let specifier: Vec<_> = interrupt
+        .specifier
+        .iter()
+        .map(|x| x.map(|x| x & u64::from(u32::MAX)))
+        .collect();
+    let value = |x: Option<u64>| x.map_or("?".to_string(), |x| x.to_string());
+    match specifier.as_slice() {
+        [kind, number, flags, ..] if gic => {
+            let (name, offset) = match kind {
+                Some(0) => ("SPI", 32),
+                Some(1) => ("PPI", 16),
+                _ => return format!("{} {}", value(*kind), value(*number)),
+            };
+            let hwirq = number
+                .and_then(|x| x.checked_add(offset))
+                .map_or("?".to_string(), |x| x.to_string());
+            let flags = flags.map_or("?", trigger);
+            format!("{name} {} (hwirq {hwirq}), {flags}", value(*number))
+        }
+        [number, flags] => format!("IRQ {}, {}", value(*number), flags.map_or("?", trigger)),
+        [number] => format!("IRQ {}", value(*number)),
+        x => x.iter().map(|x| value(*x)).collect::<Vec<_>>().join(" "),
+    }
+}
+
+// Interrupt controller for the specifier at the position, the first one if the position
+// is not inside of any specifier, e.g. on property name
+pub fn controller_at(
+    tree: &DeviceTree,
+    id: &IncludesDepot,
+    uri: &Url,
+    position: Position,
+) -> Option<usize> {
+    let (idx, property) = tree.property_at(uri, position)?;
+    if !matches!(property.name.as_str(), "interrupts" | "interrupts-extended") {
+        return None;
+    }
+
+    let specifiers = specifiers(tree, id, idx, property)?;
+    let specifier = specifiers
+        .iter()
+        .find(|x| x.cells.iter().any(|x| range_contains(&x.range, position)))
+        .or(specifiers.first())?;
+    specifier.interrupt.as_ref().map(|x| x.controller)
+}
@@ -1,13 +1,14 @@
 use crate::file_depot::FileDepot;
 use crate::utils::Symbol;
-use crate::{error, log_message};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
-use tower_lsp::lsp_types::{MessageType, Range, Url};
+use tower_lsp::lsp_types::{Range, Url};
 
 #[cfg(test)]
-use crate::info;
+use crate::{info, log_message};
+#[cfg(test)]
+use tower_lsp::lsp_types::MessageType;
 
 #[derive(Clone, Eq, Hash, PartialEq)]
 struct Label {
@@ -80,29 +81,6 @@ impl Data {
         res
     }
 
-    fn rename(&mut self, uri: &Url, old_name: &str, new_name: &str) -> Result<(), String> {
-        let old = self.label_to_symbol.remove_entry(&Label {
-            name: old_name.to_string(),
-            uri: uri.clone(),
-        });
-
-        match old {
-            None => Err(format!("Renaming non-existant label: {old_name}")),
-            Some((old_label, mut range)) => {
-                let new_name_len = u32::try_from(new_name.len()).map_err(|e| format!("{e}"))?;
-                range.end.character = range.start.character + new_name_len;
-                self.label_to_symbol.insert(
-                    Label {
-                        name: new_name.to_string(),
-                        uri: old_label.uri,
-                    },
-                    range,
-                );
-                Ok(())
-            }
-        }
-    }
-
     fn invalidate(&mut self, uri: &Url) {
         let mut v = Vec::new();
 
@@ -167,18 +145,6 @@ impl LabelsDepot {
         data.invalidate(uri);
     }
 
-    pub fn rename(&self, uri: &Url, old_name: &str, new_name: &str) {
-        let res = {
-            let mut data = self.data.lock().unwrap();
-            data.rename(uri, old_name, new_name)
-        };
-
-        match res {
-            Ok(()) => (),
-            Err(e) => error!("{}", e),
-        }
-    }
-
     pub fn get_labels_for_uri(&self, uri: &Url) -> Vec<(String, Range)> {
         self.data.lock().unwrap().get_labels_for_uri(uri)
     }
@@ -0,0 +1,407 @@
+use crate::diagnostics::{is_macro_use, walk};
+use crate::encoding::{convert_range, offset_position};
+use tower_lsp::lsp_types::Range;
+use tree_sitter::{Node, Tree};
+
+/*
+ * Macros are expanded by preprocessor before dtc sees the file, so every identifier token
+ * outside of comments and string literals is a potential macro use, including tokens in
+ * values of other macros and in preprocessor conditions.
+ */
+
+// Leaf nodes that are not subject to macro expansion
+const SKIPPED_KINDS: &[&str] = &[
+    "comment",
+    "string_literal",
+    "system_lib_string",
+    "byte_string_literal",
+    "char_literal",
+];
+
+pub struct Macro {
+    pub value: String,
+    // Parameters of function-like macro
+    pub params: Option<Vec<String>>,
+}
+
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+pub fn is_identifier(text: &str) -> bool {
+    text.chars().all(is_identifier_char) && text.chars().next().is_some_and(|x| !x.is_ascii_digit())
+}
+
+// Macro in the form of -D option: NAME, NAME=VALUE or NAME(a,b)=VALUE, returns name,
+// parameters and value. Like with cpp, macro without value is defined as 1.
+pub fn parse_define(text: &str) -> Option<(String, Option<Vec<String>>, String)> {
+    let text = text.trim();
+    let text = text.strip_prefix("-D").unwrap_or(text);
+    let (head, value) = text.split_once('=').unwrap_or((text, "1"));
+    let (name, params) = match head.split_once('(') {
+        Some((name, params)) => {
+            let params = params.strip_suffix(')')?;
+            let params: Vec<_> = params
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect();
+            if !params.iter().all(|x| is_identifier(x)) {
+                return None;
+            }
+            (name, Some(params))
+        }
+        None => (head, None),
+    };
+    is_identifier(name).then(|| (name.to_string(), params, value.trim().to_string()))
+}
+
+// Identifier tokens of the text with their byte offsets, comments and literals are skipped
+fn identifiers(text: &str) -> Vec<(usize, &str)> {
+    let mut res = Vec::new();
+    let mut chars = text.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '/' if text[i..].starts_with("//") => {
+                let end = text[i..].find('\n').map_or(text.len(), |x| i + x);
+                while chars.next_if(|x| x.0 < end).is_some() {}
+            }
+            '/' if text[i..].starts_with("/*") => {
+                let end = text[i + 2..].find("*/").map_or(text.len(), |x| i + x + 4);
+                while chars.next_if(|x| x.0 < end).is_some() {}
+            }
+            '"' | '\'' => {
+                let mut escaped = false;
+                for (_, x) in chars.by_ref() {
+                    match x {
+                        _ if escaped => escaped = false,
+                        '\\' => escaped = true,
+                        x if x == c => break,
+                        _ => (),
+                    }
+                }
+            }
+            c if is_identifier_char(c) => {
+                let mut end = i + c.len_utf8();
+                while let Some((j, x)) = chars.next_if(|x| is_identifier_char(x.1)) {
+                    end = j + x.len_utf8();
+                }
+                if !c.is_ascii_digit() {
+                    res.push((i, &text[i..end]));
+                }
+            }
+            _ => (),
+        }
+    }
+    res
+}
+
+// Ranges of tokens in the file that preprocessor would treat as the macro name
+pub fn occurrences(tree: &Tree, text: &str, name: &str) -> Vec<Range> {
+    let mut res = Vec::new();
+    walk(tree.root_node(), &mut |node| {
+        if node.child_count() > 0 || SKIPPED_KINDS.contains(&node.kind()) {
+            return;
+        }
+
+        let leaf = &text[node.byte_range()];
+        let start = convert_range(&node.range()).start;
+        for (offset, token) in identifiers(leaf) {
+            if token == name {
+                res.push(Range::new(
+                    offset_position(start, leaf, offset),
+                    offset_position(start, leaf, offset + token.len()),
+                ));
+            }
+        }
+    });
+    res
+}
+
+// Identifier inside of #if or #elif condition
+fn in_condition(node: &Node) -> bool {
+    let mut cur = *node;
+    while let Some(parent) = cur.parent() {
+        match parent.kind() {
+            "preproc_if" | "preproc_elif" => {
+                return parent.child_by_field_name("condition") == Some(cur);
+            }
+            "binary_expression"
+            | "unary_expression"
+            | "parenthesized_expression"
+            | "preproc_defined"
+            | "call_expression"
+            | "argument_list" => cur = parent,
+            _ => return false,
+        }
+    }
+    false
+}
+
+fn tokens(node: &Node, text: &str) -> Vec<(String, Range)> {
+    let leaf = &text[node.byte_range()];
+    let start = convert_range(&node.range()).start;
+    identifiers(leaf)
+        .into_iter()
+        .map(|(offset, token)| {
+            let range = Range::new(
+                offset_position(start, leaf, offset),
+                offset_position(start, leaf, offset + token.len()),
+            );
+            (token.to_string(), range)
+        })
+        .collect()
+}
+
+// Potential macro uses: identifiers in cells and arguments of macro calls, in values of other
+// macros and in preprocessor conditions
+pub fn uses(tree: &Tree, text: &str) -> Vec<(String, Range)> {
+    let mut res = Vec::new();
+    walk(tree.root_node(), &mut |node| {
+        let parent = node.parent().map(|x| x.kind());
+        match (node.kind(), parent) {
+            ("identifier", _) if is_macro_use(&node) || in_condition(&node) => {
+                let name = node.utf8_text(text.as_bytes()).unwrap().to_string();
+                res.push((name, convert_range(&node.range())));
+            }
+            ("identifier", Some("preproc_ifdef" | "preproc_undef")) => {
+                let name = node.utf8_text(text.as_bytes()).unwrap().to_string();
+                res.push((name, convert_range(&node.range())));
+            }
+            // Arguments of macro calls may fail to parse, e.g. char literals, so they are
+            // split into tokens directly
+            ("argument_list", Some("call_expression")) => {
+                let call = node.parent().unwrap();
+                let function = call.child_by_field_name("function");
+                if function.is_some_and(|x| is_macro_use(&x)) {
+                    res.append(&mut tokens(&node, text));
+                }
+            }
+            ("preproc_arg", _) => res.append(&mut tokens(&node, text)),
+            _ => (),
+        }
+    });
+    res
+}
+
+/*
+ * Arguments of macro call at the start of the text, split by top level commas. Returns length
+ * of the call arguments including parentheses, and byte offsets and text of every argument.
+ */
+fn call_arguments(text: &str) -> Option<(usize, Vec<(usize, &str)>)> {
+    if !text.starts_with('(') {
+        return None;
+    }
+
+    let mut res = Vec::new();
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    let mut start = 1;
+    let mut len = None;
+    for (i, c) in text.char_indices() {
+        match (c, quote) {
+            (_, Some(_)) if escaped => escaped = false,
+            ('\\', Some(_)) => escaped = true,
+            (c, Some(q)) if c == q => quote = None,
+            ('"' | '\'', None) => quote = Some(c),
+            ('(', None) => depth += 1,
+            (')', None) => {
+                depth -= 1;
+                if depth == 0 {
+                    len = Some(i + 1);
+                    break;
+                }
+            }
+            (',', None) if depth == 1 => {
+                res.push((start, &text[start..i]));
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    let len = len?;
+    res.push((start, &text[start..len - 1]));
+
+    // Skip leading whitespace, so that hints are placed right before arguments
+    let res = res
+        .into_iter()
+        .map(|(offset, x)| {
+            let trimmed = x.trim_start();
+            (offset + x.len() - trimmed.len(), trimmed.trim_end())
+        })
+        .filter(|x| !x.1.is_empty())
+        .collect();
+    Some((len, res))
+}
+
+// Split arguments of macro call by top level commas, returns byte offsets and arguments
+pub fn split_arguments(text: &str) -> Vec<(usize, &str)> {
+    match call_arguments(text) {
+        Some((len, res)) if len == text.len() => res,
+        _ => Vec::new(),
+    }
+}
+
+// Length of operand of defined in #if condition, either NAME or (NAME)
+fn defined_operand_len(text: &str) -> usize {
+    let skipped = text.len() - text.trim_start().len();
+    let rest = &text[skipped..];
+    let len = if rest.starts_with('(') {
+        rest.find(')').map_or(0, |x| x + 1)
+    } else {
+        rest.find(|x| !is_identifier_char(x)).unwrap_or(rest.len())
+    };
+    skipped + len
+}
+
+// Operands of ## are joined together with whitespace around the operator removed
+fn paste(text: &str) -> String {
+    let parts: Vec<_> = text.split("##").collect();
+    let last = parts.len() - 1;
+    parts
+        .iter()
+        .enumerate()
+        .map(|(i, x)| match (i, x) {
+            (0, x) if last == 0 => *x,
+            (0, x) => x.trim_end(),
+            (i, x) if i == last => x.trim_start(),
+            (_, x) => x.trim(),
+        })
+        .collect()
+}
+
+// Guard against expansions that grow without repeating any macro
+const MAX_DEPTH: usize = 64;
+
+struct Expander<'a> {
+    lookup: &'a dyn Fn(&str) -> Option<Macro>,
+    // Macros that are being expanded, like cpp does it they are not expanded again
+    disabled: Vec<String>,
+    // Operands of defined in #if conditions are not expanded
+    condition: bool,
+    // First macro that showed up in its own expansion, along with the chain leading back to it
+    recursive: Option<Vec<String>>,
+}
+
+impl Expander<'_> {
+    // Parameters are replaced with expanded arguments, except for operands of # and ##
+    fn substitute(&mut self, value: &str, params: &[String], arguments: &[&str]) -> String {
+        let mut res = String::new();
+        let mut pos = 0;
+        for (offset, name) in identifiers(value) {
+            let Some(i) = params.iter().position(|x| x == name) else {
+                continue;
+            };
+            let before = value[pos..offset].trim_end();
+            let after = value[offset + name.len()..].trim_start();
+            if let Some(x) = before.strip_suffix('#').filter(|x| !x.ends_with('#')) {
+                let quoted = arguments[i].replace('\\', "\\\\").replace('"', "\\\"");
+                res.push_str(x);
+                res.push('"');
+                res.push_str(&quoted);
+                res.push('"');
+            } else if before.ends_with("##") || after.starts_with("##") {
+                res.push_str(&value[pos..offset]);
+                res.push_str(arguments[i]);
+            } else {
+                res.push_str(&value[pos..offset]);
+                res.push_str(&self.expand(arguments[i]));
+            }
+            pos = offset + name.len();
+        }
+        res.push_str(&value[pos..]);
+        paste(&res)
+    }
+
+    // Replacement of the macro along with the length of call arguments that it consumes
+    fn replacement(&mut self, name: &str, rest: &str) -> Option<(String, usize)> {
+        if let Some(i) = self.disabled.iter().position(|x| x == name) {
+            if self.recursive.is_none() {
+                let mut chain = self.disabled[i..].to_vec();
+                chain.push(name.to_string());
+                self.recursive = Some(chain);
+            }
+            return None;
+        }
+        if self.disabled.len() > MAX_DEPTH {
+            return None;
+        }
+
+        let define = (self.lookup)(name)?;
+        let Some(params) = define.params else {
+            return Some((define.value, 0));
+        };
+
+        // Function-like macro without arguments is not expanded
+        let skipped = rest.len() - rest.trim_start().len();
+        let (len, arguments) = call_arguments(&rest[skipped..])?;
+        let arguments: Vec<_> = arguments.into_iter().map(|x| x.1).collect();
+        if arguments.len() != params.len() {
+            return None;
+        }
+        let value = self.substitute(&define.value, &params, &arguments);
+        Some((value, skipped + len))
+    }
+
+    fn expand(&mut self, text: &str) -> String {
+        let mut res = String::new();
+        let mut pos = 0;
+        for (offset, name) in identifiers(text) {
+            // Identifiers in arguments that are already consumed
+            if offset < pos {
+                continue;
+            }
+            res.push_str(&text[pos..offset]);
+            pos = offset + name.len();
+
+            if self.condition && name == "defined" {
+                let len = defined_operand_len(&text[pos..]);
+                res.push_str(name);
+                res.push_str(&text[pos..pos + len]);
+                pos += len;
+                continue;
+            }
+
+            match self.replacement(name, &text[pos..]) {
+                Some((value, len)) => {
+                    pos += len;
+                    self.disabled.push(name.to_string());
+                    let value = self.expand(&value);
+                    self.disabled.pop();
+                    res.push_str(&value);
+                }
+                None => res.push_str(name),
+            }
+        }
+        res.push_str(&text[pos..]);
+        res
+    }
+}
+
+// Text with all macros expanded recursively, comments and literals are kept as is
+pub fn expand(text: &str, lookup: &dyn Fn(&str) -> Option<Macro>) -> String {
+    let mut expander = Expander {
+        lookup,
+        disabled: Vec::new(),
+        condition: false,
+        recursive: None,
+    };
+    expander.expand(text)
+}
+
+// Expansion for evaluation, fails with the chain of macros if one of them is recursive
+pub fn expand_checked(
+    text: &str,
+    lookup: &dyn Fn(&str) -> Option<Macro>,
+    condition: bool,
+) -> Result<String, Vec<String>> {
+    let mut expander = Expander {
+        lookup,
+        disabled: Vec::new(),
+        condition,
+        recursive: None,
+    };
+    let res = expander.expand(text);
+    expander.recursive.map_or(Ok(res), Err)
+}
@@ -0,0 +1,99 @@
+use crate::file_depot::FileDepot;
+use crate::utils::Symbol;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use tower_lsp::lsp_types::{Range, Url};
+
+/*
+ * Uses of macros in cells, values of other macros and preprocessor conditions. Uses are
+ * looked up in all connected files, same as references to labels.
+ */
+
+#[derive(Clone, Eq, Hash, PartialEq)]
+struct Use {
+    uri: Url,
+    name: String,
+}
+
+impl Use {
+    fn new(uri: &Url, name: &str) -> Use {
+        Use {
+            uri: uri.clone(),
+            name: name.to_string(),
+        }
+    }
+}
+
+struct Data {
+    use_to_symbols: HashMap<Use, Vec<Range>>,
+    fd: FileDepot,
+}
+
+impl Data {
+    fn new(fd: &FileDepot) -> Data {
+        Data {
+            use_to_symbols: HashMap::new(),
+            fd: fd.clone(),
+        }
+    }
+
+    fn add_use(&mut self, name: &str, uri: &Url, range: Range) {
+        let v = self.use_to_symbols.entry(Use::new(uri, name)).or_default();
+        if !v.contains(&range) {
+            v.push(range);
+        }
+    }
+
+    fn find_uses(&self, uri: &Url, name: &str) -> Vec<Symbol> {
+        let mut files: HashSet<Url> = self.fd.get_component(uri).into_iter().collect();
+        files.insert(uri.clone());
+
+        let mut res = Vec::new();
+        for uri in files {
+            if let Some(v) = self.use_to_symbols.get(&Use::new(&uri, name)) {
+                res.extend(v.iter().map(|x| Symbol::new(uri.clone(), *x)));
+            }
+        }
+        res
+    }
+
+    fn invalidate(&mut self, uri: &Url) {
+        self.use_to_symbols.retain(|k, _| k.uri != *uri);
+    }
+
+    #[cfg(test)]
+    fn size(&self) -> usize {
+        self.use_to_symbols.keys().count()
+    }
+}
+
+#[derive(Clone)]
+pub struct MacrosDepot {
+    data: Arc<Mutex<Data>>,
+}
+
+impl MacrosDepot {
+    pub fn new(fd: &FileDepot) -> MacrosDepot {
+        MacrosDepot {
+            data: Arc::new(Mutex::new(Data::new(fd))),
+        }
+    }
+
+    pub fn add_use(&self, name: &str, uri: &Url, range: Range) {
+        self.data.lock().unwrap().add_use(name, uri, range);
+    }
+
+    pub fn find_uses(&self, uri: &Url, name: &str) -> Vec<Symbol> {
+        self.data.lock().unwrap().find_uses(uri, name)
+    }
+
+    pub fn invalidate(&self, uri: &Url) {
+        self.data.lock().unwrap().invalidate(uri);
+    }
+
+    #[cfg(test)]
+    pub fn size(&self) -> usize {
+        self.data.lock().unwrap().size()
+    }
+}
@@ -1,4 +1,6 @@
 use config::Config;
+use encoding::convert_range;
+use encoding::Encoding;
 use logger::log_message;
 use logger::Logger;
 use std::collections::HashMap;
@@ -11,17 +13,34 @@ use tower_lsp::lsp_types::*;
 use tower_lsp::Client;
 use tower_lsp::{LanguageServer, LspService, Server};
 use tree_sitter::Parser;
-use tree_sitter::Point;
-use utils::convert_range;
 
+mod addresses;
+mod code_actions;
+mod compare;
+mod compiler;
 mod config;
+mod device_tree;
 mod diagnostics;
+mod encoding;
+mod expressions;
+mod fdt;
 mod file_depot;
+mod folding;
+mod formatting;
+mod hover;
 mod includes_depot;
+mod inlay_hints;
+mod interrupts;
 mod labels_depot;
 mod logger;
+mod macros;
+mod macros_depot;
+mod preprocessor;
 mod references_depot;
+mod selection;
+mod trees_depot;
 mod utils;
+mod validation;
 mod workspace;
 
 #[cfg(test)]
@@ -44,31 +63,144 @@ impl Backend {
         }
     }
 
-    async fn get_includes_path(&self) -> String {
-        let default = ".".to_string();
-
+    async fn get_config(&self, key: &str) -> Option<LSPAny> {
         let cfg_item = vec![ConfigurationItem {
             scope_uri: None,
             section: Some("dts-lsp".to_string()),
         }];
 
-        let cfg = match self.client.clone() {
-            None => return default,
-            Some(x) => x.configuration(cfg_item).await,
-        };
+        let cfg = self.client.clone()?.configuration(cfg_item).await;
 
         info!("got cfg: {:?}", cfg);
 
-        if let Ok(cfg) = cfg {
-            let cfg = &cfg[0];
-            let cfg = cfg.get("bindings_includes");
-            if let Some(cfg) = cfg {
-                return cfg.to_string();
+        cfg.ok()?.first()?.get(key).cloned()
+    }
+
+    // Array of strings from settings, other values are ignored
+    fn strings(value: Option<&LSPAny>) -> Vec<String> {
+        value
+            .and_then(|x| x.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|x| x.as_str().map(String::from))
+            .collect()
+    }
+
+    async fn configure_predefined(&self) {
+        let defines = Self::strings(self.get_config("defines").await.as_ref());
+        let undefines = Self::strings(self.get_config("undefines").await.as_ref());
+        info!("predefined: {defines:?}, undefined: {undefines:?}");
+        self.data.id.set_predefined(&defines, &undefines);
+
+        // Board specific macros, keyed by path relative to workspace root
+        let boards = self.get_config("boards").await;
+        if let (Some(boards), Some(root)) = (boards, self.data.fd.get_root_dir()) {
+            for (path, x) in boards.as_object().into_iter().flatten() {
+                match root.join(path) {
+                    Ok(uri) => self.data.id.set_board_predefined(
+                        &uri,
+                        &Self::strings(x.get("defines")),
+                        &Self::strings(x.get("undefines")),
+                    ),
+                    Err(e) => error!("Invalid board path {path}: {e}"),
+                }
             }
         }
 
-        default
+        // Inactive blocks of cached trees depend on predefined macros
+        self.data.td.clear();
+    }
+
+    // Custom request that returns decompiled source of .dtb or .dtbo file, handlers of custom
+    // requests have to be async
+    #[allow(clippy::unused_async)]
+    async fn decompile(&self, params: TextDocumentIdentifier) -> Result<Option<String>> {
+        Ok(self.data.decompile(&params.uri))
     }
+
+    async fn get_includes_path(&self) -> String {
+        self.get_config("bindings_includes")
+            .await
+            .map_or(".".to_string(), |x| x.to_string())
+    }
+
+    // Request is valid, but the server can't fulfil it, e.g. edits overlap
+    fn request_failed(message: String) -> Error {
+        let mut err = Error::new(tower_lsp::jsonrpc::ErrorCode::ServerError(REQUEST_FAILED));
+        err.message = message.into();
+        err
+    }
+
+    fn rename_macro(&self, uri: &Url, name: &str, new_name: &str) -> Result<Option<WorkspaceEdit>> {
+        let edits = match self.data.rename_macro(uri, name, new_name) {
+            Ok(x) => x,
+            Err(e) => {
+                let mut err = Error::new(tower_lsp::jsonrpc::ErrorCode::InvalidParams);
+                err.message = e.into();
+                return Err(err);
+            }
+        };
+
+        self.data
+            .workspace_edit(edits)
+            .map(Some)
+            .map_err(Self::request_failed)
+    }
+
+    fn locations_to_client(&self, locations: Vec<Location>) -> Vec<Location> {
+        locations
+            .into_iter()
+            .map(|x| self.data.fd.location_to_client(x))
+            .collect()
+    }
+}
+
+// Returns text of the location with all macros expanded, empty range stands for the property
+// at the position
+const EXPAND_MACROS: &str = "dts-lsp.expandMacros";
+const COMPARE_BLOB: &str = "dts-lsp.compareBlob";
+
+// RequestFailed error code of LSP, it is not known to jsonrpc crate
+const REQUEST_FAILED: i64 = -32803;
+
+// Location passed as command argument in the form of LSP Location structure
+fn location_argument(value: &LSPAny) -> Option<Location> {
+    let position = |x: &LSPAny| {
+        let line = u32::try_from(x.get("line")?.as_u64()?).ok()?;
+        let character = u32::try_from(x.get("character")?.as_u64()?).ok()?;
+        Some(Position::new(line, character))
+    };
+    let uri = Url::parse(value.get("uri")?.as_str()?).ok()?;
+    let range = value.get("range")?;
+    let range = Range::new(position(range.get("start")?)?, position(range.get("end")?)?);
+    Some(Location::new(uri, range))
+}
+
+fn url_argument(value: &LSPAny) -> Option<Url> {
+    Url::parse(value.as_str()?).ok()
+}
+
+fn object(items: Vec<(&str, LSPAny)>) -> LSPAny {
+    LSPAny::Object(items.into_iter().map(|(k, v)| (k.to_string(), v)).collect())
+}
+
+fn location_value(location: &Location) -> LSPAny {
+    let position = |x: Position| {
+        object(vec![
+            ("line", x.line.into()),
+            ("character", x.character.into()),
+        ])
+    };
+    object(vec![
+        ("uri", location.uri.to_string().into()),
+        (
+            "range",
+            object(vec![
+                ("start", position(location.range.start)),
+                ("end", position(location.range.end)),
+            ]),
+        ),
+    ])
 }
 
 #[tower_lsp::async_trait]
@@ -82,8 +214,13 @@ impl LanguageServer for Backend {
         };
         self.data.fd.set_root_dir(&uri);
 
+        let encoding = Encoding::negotiate(&params.capabilities);
+        info!("Position encoding: {encoding:?}");
+        self.data.fd.set_encoding(encoding);
+
         Ok(InitializeResult {
             capabilities: ServerCapabilities {
+                position_encoding: Some(encoding.kind()),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
                     TextDocumentSyncKind::FULL,
                 )),
@@ -96,6 +233,26 @@ impl LanguageServer for Backend {
                     },
                 })),
                 document_symbol_provider: Some(OneOf::Left(true)),
+                hover_provider: Some(HoverProviderCapability::Simple(true)),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                document_formatting_provider: Some(OneOf::Left(true)),
+                document_range_formatting_provider: Some(OneOf::Left(true)),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![EXPAND_MACROS.to_string(), COMPARE_BLOB.to_string()],
+                    ..Default::default()
+                }),
+                selection_range_provider: Some(SelectionRangeProviderCapability::Simple(true)),
+                folding_range_provider: Some(FoldingRangeProviderCapability::Simple(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Options(
+                    CodeActionOptions {
+                        code_action_kinds: Some(vec![
+                            CodeActionKind::QUICKFIX,
+                            CodeActionKind::REFACTOR_REWRITE,
+                            CodeActionKind::REFACTOR_EXTRACT,
+                        ]),
+                        ..Default::default()
+                    },
+                )),
                 ..ServerCapabilities::default()
             },
             ..Default::default()
@@ -106,6 +263,25 @@ impl LanguageServer for Backend {
         let x = self.get_includes_path().await;
         info!("include_path: {x}");
 
+        // Base tree which overlays are applied to
+        if let Some(x) = self.get_config("overlay_base").await {
+            if let Some(x) = x.as_str() {
+                info!("overlay_base: {x}");
+                self.data.fd.set_overlay_base(x);
+            }
+        }
+
+        // Macros passed to preprocessor with -D and -U options
+        self.configure_predefined().await;
+
+        // Device tree compiler, it is run on save when path to it is set
+        let setting = |x: Option<LSPAny>| x.and_then(|x| x.as_str().map(String::from));
+        let dtc = setting(self.get_config("dtc").await);
+        let cpp = setting(self.get_config("cpp").await);
+        let include_paths = Self::strings(self.get_config("include_paths").await.as_ref());
+        info!("dtc: {dtc:?}, cpp: {cpp:?}, include_paths: {include_paths:?}");
+        self.data.cc.configure(dtc, cpp, include_paths);
+
         info!("server initialized!");
         #[cfg(feature = "walkdir")]
         if self.config.full_scan {
@@ -127,6 +303,7 @@ impl LanguageServer for Backend {
 
         let text = params.text_document.text.as_str();
         self.data.handle_file(uri, Some(text.to_string()));
+        self.data.fd.set_version(uri, params.text_document.version);
 
         // No need to open other files if full scan was done
         if self.config.process_neighbours && !self.config.full_scan {
@@ -138,9 +315,10 @@ impl LanguageServer for Backend {
         &self,
         input: GotoDefinitionParams,
     ) -> Result<Option<GotoDefinitionResponse>> {
-        let location = input.text_document_position_params.position;
-        let location = Point::new(location.line as usize, location.character as usize);
         let uri = input.text_document_position_params.text_document.uri;
+        let position = input.text_document_position_params.position;
+        let position = self.data.fd.position_from_client(&uri, position);
+        let location = encoding::point(position);
         let Some(text) = self.data.fd.get_text(&uri) else {
             return Ok(None);
         };
@@ -166,6 +344,7 @@ impl LanguageServer for Backend {
                         .into_iter()
                         .map(|x| Location::new(x.uri, x.range))
                         .collect();
+                    let res = self.locations_to_client(res);
 
                     match res.len() {
                         0 => Ok(None),
@@ -176,11 +355,18 @@ impl LanguageServer for Backend {
                 ("identifier", _) => match self.data.id.find_define(&uri, label) {
                     None => Ok(None),
                     Some(x) => {
-                        let res = Location::new(x.uri, x.range);
+                        let res = self
+                            .data
+                            .fd
+                            .location_to_client(Location::new(x.uri, x.range));
                         Ok(Some(GotoDefinitionResponse::Scalar(res)))
                     }
                 },
-                _ => Ok(None),
+                // Interrupt specifier cells lead to the interrupt controller
+                _ => Ok(self
+                    .data
+                    .interrupt_controller(&uri, position)
+                    .map(|x| GotoDefinitionResponse::Scalar(self.data.fd.location_to_client(x)))),
             };
         }
 
@@ -188,9 +374,9 @@ impl LanguageServer for Backend {
     }
 
     async fn references(&self, params: ReferenceParams) -> Result<Option<Vec<Location>>> {
-        let location = params.text_document_position.position;
-        let location = Point::new(location.line as usize, location.character as usize);
         let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let location = encoding::point(self.data.fd.position_from_client(&uri, position));
 
         let Some(text) = self.data.fd.get_text(&uri) else {
             warn!("No text found for file {uri}");
@@ -207,15 +393,27 @@ impl LanguageServer for Backend {
             .named_descendant_for_point_range(location, location)
         {
             let label = node.utf8_text(text.as_bytes()).unwrap();
+            let include_declaration = params.context.include_declaration;
+            let parent_kind = node.parent().map(|x| x.kind());
 
-            if let (Some(parent), v) = (node.parent(), self.data.rd.find_references(&uri, label)) {
-                if parent.kind() == "node" {
-                    let mut res = Vec::new();
-                    for x in v {
-                        res.push(Location::new(x.uri, x.range));
-                    }
-                    return Ok(Some(res));
-                }
+            // Label definition or usage, e.g. uart0: serial {}, <&uart0> or &uart0 {}
+            if let ("identifier", Some("node" | "reference")) = (node.kind(), parent_kind) {
+                let res = self
+                    .data
+                    .find_label_references(&uri, label, include_declaration);
+                return Ok(Some(self.locations_to_client(res)));
+            }
+
+            if let Some(path) = utils::path_reference(&node, &text) {
+                let res = self
+                    .data
+                    .find_path_references(&uri, path, include_declaration);
+                return Ok(Some(self.locations_to_client(res)));
+            }
+
+            if node.kind() == "identifier" {
+                let res = self.data.find_macro_uses(&uri, label, include_declaration);
+                return Ok(res.map(|x| self.locations_to_client(x)));
             }
         }
         Ok(None)
@@ -225,9 +423,8 @@ impl LanguageServer for Backend {
         &self,
         params: TextDocumentPositionParams,
     ) -> Result<Option<PrepareRenameResponse>> {
-        let location = params.position;
-        let location = Point::new(location.line as usize, location.character as usize);
         let uri = params.text_document.uri;
+        let location = encoding::point(self.data.fd.position_from_client(&uri, params.position));
         let Some(text) = self.data.fd.get_text(&uri) else {
             warn!("No text found for file {uri}");
             return Ok(None);
@@ -247,9 +444,13 @@ impl LanguageServer for Backend {
 
             let labels = self.data.ld.find_label(&uri, name);
             let references = self.data.rd.find_references(&uri, name);
+            let define = self.data.id.find_define(&uri, name);
 
-            if labels.len() + references.len() > 0 {
-                return Ok(Some(PrepareRenameResponse::Range(convert_range(&range))));
+            if labels.len() + references.len() > 0
+                || (node.kind() == "identifier" && define.is_some())
+            {
+                let range = self.data.fd.range_to_client(&uri, convert_range(&range));
+                return Ok(Some(PrepareRenameResponse::Range(range)));
             }
         }
 
@@ -257,9 +458,9 @@ impl LanguageServer for Backend {
     }
 
     async fn rename(&self, params: RenameParams) -> Result<Option<WorkspaceEdit>> {
-        let location = params.text_document_position.position;
-        let location = Point::new(location.line as usize, location.character as usize);
         let uri = params.text_document_position.text_document.uri;
+        let position = params.text_document_position.position;
+        let location = encoding::point(self.data.fd.position_from_client(&uri, position));
         let Some(text) = self.data.fd.get_text(&uri) else {
             warn!("No text found for file {uri}");
             return Ok(None);
@@ -280,30 +481,21 @@ impl LanguageServer for Backend {
             let labels = self.data.ld.find_label(&uri, name);
             let references = self.data.rd.find_references(&uri, name);
 
-            for label in &labels {
-                self.data.ld.rename(&label.uri, name, &params.new_name);
-            }
-
-            for reference in &references {
-                self.data.rd.rename(&reference.uri, name, &params.new_name);
+            if labels.is_empty() && references.is_empty() && node.kind() == "identifier" {
+                return self.rename_macro(&uri, name, &params.new_name);
             }
 
-            // TODO: check that labels in single file are ordered from bottom to top
             for symbol in labels.iter().chain(references.iter()) {
                 let e = result.entry(symbol.uri.clone()).or_default();
                 e.push(TextEdit::new(symbol.range, params.new_name.clone()));
             }
 
-            for (uri, edits) in &result {
-                self.data.fd.apply_edits(uri, edits);
-            }
-
             if !result.is_empty() {
-                return Ok(Some(WorkspaceEdit {
-                    changes: Some(result),
-                    document_changes: None,
-                    change_annotations: None,
-                }));
+                return self
+                    .data
+                    .workspace_edit(result)
+                    .map(Some)
+                    .map_err(Self::request_failed);
             }
         }
 
@@ -320,6 +512,7 @@ impl LanguageServer for Backend {
         // TODO: provide all nodes as STRUCT symbols
 
         for (name, range) in self.data.ld.get_labels_for_uri(&url) {
+            let range = self.data.fd.range_to_client(&url, range);
             symbols.push(
                 #[allow(deprecated)]
                 DocumentSymbol {
@@ -338,6 +531,103 @@ impl LanguageServer for Backend {
         Ok(Some(DocumentSymbolResponse::Nested(symbols)))
     }
 
+    async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
+        let uri = params.text_document_position_params.text_document.uri;
+        let position = params.text_document_position_params.position;
+        let position = self.data.fd.position_from_client(&uri, position);
+
+        Ok(self.data.hover(&uri, position).map(|x| Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: x,
+            }),
+            range: None,
+        }))
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri;
+        let range = self.data.fd.range_from_client(&uri, params.range);
+        Ok(Some(self.data.inlay_hints(&uri, range)))
+    }
+
+    async fn formatting(&self, params: DocumentFormattingParams) -> Result<Option<Vec<TextEdit>>> {
+        Ok(self.data.format(&params.text_document.uri, None))
+    }
+
+    async fn range_formatting(
+        &self,
+        params: DocumentRangeFormattingParams,
+    ) -> Result<Option<Vec<TextEdit>>> {
+        let uri = params.text_document.uri;
+        let range = self.data.fd.range_from_client(&uri, params.range);
+        Ok(self.data.format(&uri, Some(range)))
+    }
+
+    async fn folding_range(&self, params: FoldingRangeParams) -> Result<Option<Vec<FoldingRange>>> {
+        Ok(self.data.folding_ranges(&params.text_document.uri))
+    }
+
+    async fn selection_range(
+        &self,
+        params: SelectionRangeParams,
+    ) -> Result<Option<Vec<SelectionRange>>> {
+        let uri = params.text_document.uri;
+        let positions: Vec<Position> = params
+            .positions
+            .into_iter()
+            .map(|x| self.data.fd.position_from_client(&uri, x))
+            .collect();
+        Ok(self.data.selection_ranges(&uri, &positions))
+    }
+
+    async fn execute_command(&self, params: ExecuteCommandParams) -> Result<Option<LSPAny>> {
+        match params.command.as_str() {
+            EXPAND_MACROS => {
+                let Some(location) = params.arguments.first().and_then(location_argument) else {
+                    return Err(Error::invalid_params("Expected location argument"));
+                };
+                let uri = location.uri;
+                let range = self.data.fd.range_from_client(&uri, location.range);
+                Ok(self.data.expand_macros(&uri, range).map(LSPAny::String))
+            }
+            COMPARE_BLOB => {
+                let urls: Vec<_> = params.arguments.iter().filter_map(url_argument).collect();
+                let [source, blob] = urls.as_slice() else {
+                    return Err(Error::invalid_params("Expected source and blob URIs"));
+                };
+                let Some(res) = self.data.compare_blob(source, blob) else {
+                    return Ok(None);
+                };
+                let res = res.into_iter().map(|x| {
+                    let optional = |x: Option<String>| x.map_or(LSPAny::Null, LSPAny::String);
+                    let location = x
+                        .location
+                        .map(|x| location_value(&self.data.fd.location_to_client(x)));
+                    object(vec![
+                        ("path", x.path.into()),
+                        ("property", optional(x.property)),
+                        ("message", x.message.into()),
+                        ("location", location.unwrap_or(LSPAny::Null)),
+                        ("source", optional(x.source)),
+                        ("blob", optional(x.blob)),
+                    ])
+                });
+                Ok(Some(LSPAny::Array(res.collect())))
+            }
+            _ => Err(Error::method_not_found()),
+        }
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        let range = self.data.fd.range_from_client(&uri, params.range);
+        let actions = self
+            .data
+            .code_actions(&uri, range, &params.context.diagnostics);
+        Ok(Some(actions))
+    }
+
     async fn did_close(&self, params: DidCloseTextDocumentParams) {
         info!("Close file: {}", params.text_document.uri);
     }
@@ -349,10 +639,12 @@ impl LanguageServer for Backend {
 
         let text = &params.content_changes[0].text;
         self.data.handle_file(uri, Some(text.to_string()));
+        self.data.fd.set_version(uri, params.text_document.version);
     }
 
     async fn did_save(&self, params: DidSaveTextDocumentParams) {
         info!("Save file: {}", params.text_document.uri);
+        self.data.compile(&params.text_document.uri).await;
     }
 }
 
@@ -361,10 +653,12 @@ async fn main() {
     let stdin = tokio::io::stdin();
     let stdout = tokio::io::stdout();
 
-    let (service, socket) = LspService::new(|client| {
+    let (service, socket) = LspService::build(|client| {
         let handle = tokio::runtime::Handle::current();
         Logger::set(Logger::Lsp(handle.clone(), client.clone()));
         Backend::new(handle, client, config::get())
-    });
+    })
+    .custom_method("dts-lsp/decompile", Backend::decompile)
+    .finish();
     Server::new(stdin, stdout, socket).serve(service).await;
 }
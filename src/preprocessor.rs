@@ -0,0 +1,106 @@
+use crate::encoding::convert_range;
+use crate::expressions;
+use crate::includes_depot::IncludesDepot;
+use tower_lsp::lsp_types::{Position, Range, Url};
+use tree_sitter::{Node, Tree};
+
+/*
+ * Conditionals are evaluated with macros visible to the file in the current board context.
+ * Macros defined later in the same file are ignored, so that include guards don't disable
+ * contents of the header. Branches are not selected if some condition can't be evaluated.
+ */
+
+// Whether the branch of conditional is taken, None if condition can't be evaluated
+pub fn is_active(node: &Node, uri: &Url, text: &str, id: &IncludesDepot) -> Option<bool> {
+    let start = convert_range(&node.range()).start;
+    let lookup = |name: &str| {
+        let define = id.find_define(uri, name);
+        if define.is_some_and(|x| x.uri == *uri && x.range.start > start) {
+            return None;
+        }
+        id.find_macro(uri, name)
+    };
+
+    match node.kind() {
+        "preproc_if" | "preproc_elif" => {
+            let condition = node.child_by_field_name("condition")?;
+            expressions::condition(&condition, text, &lookup).ok()
+        }
+        "preproc_ifdef" | "preproc_elifdef" => {
+            let name = node.child_by_field_name("name")?;
+            let defined = lookup(name.utf8_text(text.as_bytes()).unwrap()).is_some();
+            let negated = node
+                .child(0)
+                .is_some_and(|x| matches!(x.kind(), "#ifndef" | "#elifndef"));
+            Some(defined != negated)
+        }
+        "preproc_else" => Some(true),
+        _ => None,
+    }
+}
+
+fn line(row: usize) -> Position {
+    Position::new(u32::try_from(row).unwrap(), 0)
+}
+
+// Lines between the directive of the branch and the next #elif, #else or #endif
+fn branch_lines(node: &Node, endif: usize) -> Option<Range> {
+    let end = node
+        .child_by_field_name("alternative")
+        .map_or(endif, |x| x.start_position().row);
+    let start = node.start_position().row + 1;
+    (end > start).then(|| Range::new(line(start), line(end)))
+}
+
+fn conditional(node: &Node, uri: &Url, text: &str, id: &IncludesDepot, res: &mut Vec<Range>) {
+    let mut cursor = node.walk();
+    let endif = node
+        .children(&mut cursor)
+        .find(|x| x.kind() == "#endif")
+        .map_or(node.end_position().row, |x| x.start_position().row);
+
+    let mut branch = Some(*node);
+    let mut taken = false;
+    while let Some(x) = branch {
+        let active = if taken {
+            Some(false)
+        } else {
+            is_active(&x, uri, text, id)
+        };
+        match active {
+            Some(true) => {
+                taken = true;
+                regions(&x, uri, text, id, res);
+            }
+            Some(false) => res.extend(branch_lines(&x, endif)),
+            // Branches after the one that can't be evaluated are unknown too
+            None => {
+                while let Some(x) = branch {
+                    regions(&x, uri, text, id, res);
+                    branch = x.child_by_field_name("alternative");
+                }
+                return;
+            }
+        }
+        branch = x.child_by_field_name("alternative");
+    }
+}
+
+fn regions(node: &Node, uri: &Url, text: &str, id: &IncludesDepot, res: &mut Vec<Range>) {
+    let mut cursor = node.walk();
+    for child in node.named_children(&mut cursor) {
+        match child.kind() {
+            "preproc_if" | "preproc_ifdef" => conditional(&child, uri, text, id, res),
+            // Alternatives are handled along with the conditional they belong to
+            "preproc_elif" | "preproc_elifdef" | "preproc_else" => (),
+            _ => regions(&child, uri, text, id, res),
+        }
+    }
+}
+
+// Line ranges of the branches that are skipped by preprocessor
+pub fn inactive_regions(tree: &Tree, uri: &Url, text: &str, id: &IncludesDepot) -> Vec<Range> {
+    let mut res = Vec::new();
+    regions(&tree.root_node(), uri, text, id, &mut res);
+    res
+}
@@ -1,13 +1,14 @@
 use crate::file_depot::FileDepot;
 use crate::utils::Symbol;
-use crate::{error, log_message};
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
-use tower_lsp::lsp_types::{MessageType, Range, Url};
+use tower_lsp::lsp_types::{Range, Url};
 
 #[cfg(test)]
-use crate::info;
+use crate::{info, log_message};
+#[cfg(test)]
+use tower_lsp::lsp_types::MessageType;
 
 /*
  * 1. Add all references to map Reference (similar to Label) -> Vec[Range],
@@ -89,31 +90,6 @@ impl Data {
         }
     }
 
-    fn rename(&mut self, uri: &Url, old_name: &str, new_name: &str) -> Result<(), String> {
-        let old = self.reference_to_symbols.remove_entry(&Reference {
-            name: old_name.to_string(),
-            uri: uri.clone(),
-        });
-
-        match old {
-            None => Err(format!("Renaming non-existant label: {old_name}")),
-            Some((old_label, mut ranges)) => {
-                for range in &mut ranges {
-                    let new_name_len = u32::try_from(new_name.len()).map_err(|e| format!("{e}"))?;
-                    range.end.character = range.start.character + new_name_len;
-                }
-                self.reference_to_symbols.insert(
-                    Reference {
-                        name: new_name.to_string(),
-                        uri: old_label.uri,
-                    },
-                    ranges,
-                );
-                Ok(())
-            }
-        }
-    }
-
     #[cfg(test)]
     fn size(&self) -> usize {
         self.reference_to_symbols.keys().count()
@@ -158,18 +134,6 @@ impl ReferencesDepot {
         data.invalidate(uri);
     }
 
-    pub fn rename(&self, uri: &Url, old_name: &str, new_name: &str) {
-        let res = {
-            let mut data = self.data.lock().unwrap();
-            data.rename(uri, old_name, new_name)
-        };
-
-        match res {
-            Ok(()) => (),
-            Err(e) => error!("{}", e),
-        }
-    }
-
     #[cfg(test)]
     pub fn size(&self) -> usize {
         let data = self.data.lock().unwrap();
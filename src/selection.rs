@@ -0,0 +1,40 @@
+use crate::encoding::{convert_range, point};
+use tower_lsp::lsp_types::{Position, Range};
+use tree_sitter::{Node, Tree};
+
+// Braces of the node along with everything in between
+fn body(node: &Node) -> Option<Range> {
+    let mut cursor = node.walk();
+    let mut children = node.children(&mut cursor);
+    let open = children.find(|x| x.kind() == "{")?;
+    let close = children.find(|x| x.kind() == "}")?;
+    Some(Range::new(
+        convert_range(&open.range()).start,
+        convert_range(&close.range()).end,
+    ))
+}
+
+// Ranges of the named syntax nodes enclosing the position, from innermost to the whole document.
+// Node body is a separate step between its contents and the node itself.
+pub fn selection_ranges(tree: &Tree, position: Position) -> Vec<Range> {
+    let point = point(position);
+    let mut cur = tree
+        .root_node()
+        .named_descendant_for_point_range(point, point);
+    let mut res: Vec<Range> = Vec::new();
+
+    while let Some(node) = cur {
+        let range = convert_range(&node.range());
+        if node.kind() == "node" {
+            let inner = res.last().copied();
+            if let Some(body) = body(&node).filter(|x| inner.is_some_and(|y| x.start < y.start)) {
+                res.push(body);
+            }
+        }
+        if res.last() != Some(&range) {
+            res.push(range);
+        }
+        cur = node.parent();
+    }
+    res
+}
@@ -0,0 +1,80 @@
+use crate::device_tree::DeviceTree;
+use crate::utils::is_overlay;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tower_lsp::lsp_types::Url;
+
+/*
+ * Merged trees of top-level files. Building a tree means preprocessing and merging all of the
+ * files it consists of, so trees are kept until any of those files changes.
+ */
+
+struct Data {
+    trees: HashMap<Url, Arc<DeviceTree>>,
+    // Incremented on every invalidation, trees built from outdated text are not stored
+    generation: u64,
+}
+
+impl Data {
+    fn new() -> Data {
+        Data {
+            trees: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    fn invalidate(&mut self, roots: &[Url]) {
+        self.generation += 1;
+        for uri in roots {
+            self.trees.remove(uri);
+        }
+
+        // Overlays are built on top of the base tree, which is not one of their roots
+        self.trees.retain(|uri, _| !is_overlay(uri));
+    }
+}
+
+#[derive(Clone)]
+pub struct TreesDepot {
+    data: Arc<Mutex<Data>>,
+}
+
+impl TreesDepot {
+    pub fn new() -> TreesDepot {
+        TreesDepot {
+            data: Arc::new(Mutex::new(Data::new())),
+        }
+    }
+
+    // Cached tree of the top-level file, tree is built without holding the lock
+    pub fn get_or_build<F>(&self, uri: &Url, build: F) -> Arc<DeviceTree>
+    where
+        F: FnOnce() -> DeviceTree,
+    {
+        let generation = {
+            let data = self.data.lock().unwrap();
+            if let Some(x) = data.trees.get(uri) {
+                return x.clone();
+            }
+            data.generation
+        };
+
+        let tree = Arc::new(build());
+        let mut data = self.data.lock().unwrap();
+        if data.generation == generation {
+            data.trees.insert(uri.clone(), tree.clone());
+        }
+        tree
+    }
+
+    // Drop trees of top-level files that include a changed file
+    pub fn invalidate(&self, roots: &[Url]) {
+        self.data.lock().unwrap().invalidate(roots);
+    }
+
+    pub fn clear(&self) {
+        let mut data = self.data.lock().unwrap();
+        data.generation += 1;
+        data.trees.clear();
+    }
+}
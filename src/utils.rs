@@ -17,17 +17,35 @@ impl Symbol {
     }
 }
 
-pub fn convert_range(range: &tree_sitter::Range) -> Range {
-    Range::new(
-        Position::new(
-            u32::try_from(range.start_point.row).unwrap(),
-            u32::try_from(range.start_point.column).unwrap(),
-        ),
-        Position::new(
-            u32::try_from(range.end_point.row).unwrap(),
-            u32::try_from(range.end_point.column).unwrap(),
-        ),
-    )
+pub fn range_contains(range: &Range, position: Position) -> bool {
+    range.start <= position && position <= range.end
+}
+
+// Parse C integer literal, e.g. 0x10, 010, 10UL
+pub fn parse_integer(text: &str) -> Option<u64> {
+    let text = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    if let Some(x) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+        u64::from_str_radix(x, 16).ok()
+    } else if let Some(x) = text.strip_prefix('0').filter(|x| !x.is_empty()) {
+        u64::from_str_radix(x, 8).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// Parse contents of byte string, e.g. [00 11 2233], bytes may go without separators
+pub fn parse_bytes(text: &str) -> Option<Vec<u8>> {
+    let text = text.strip_prefix('[')?.strip_suffix(']')?;
+    let mut res = Vec::new();
+    for word in text.split_whitespace() {
+        if word.len() % 2 != 0 || !word.chars().all(|x| x.is_ascii_hexdigit()) {
+            return None;
+        }
+        for i in (0..word.len()).step_by(2) {
+            res.push(u8::from_str_radix(&word[i..i + 2], 16).ok()?);
+        }
+    }
+    Some(res)
 }
 
 pub fn extension_one_of(url: &Url, exts: &[&str]) -> bool {
@@ -43,10 +61,36 @@ pub fn extension_one_of(url: &Url, exts: &[&str]) -> bool {
     false
 }
 
+// Strip quotes or angle brackets from include directive path
+pub fn include_path(path: &str) -> &str {
+    let path = path.trim_matches('"');
+    let path = path.trim_matches('<');
+    path.trim_matches('>')
+}
+
 pub fn is_header(uri: &Url) -> bool {
     extension_one_of(uri, &["h"])
 }
 
+// Compiled device tree, it is decompiled to source when loaded
+pub fn is_blob(uri: &Url) -> bool {
+    extension_one_of(uri, &["dtb", "dtbo"])
+}
+
+pub fn is_overlay(uri: &Url) -> bool {
+    extension_one_of(uri, &["dtso", "dtbo"])
+}
+
+// Overlays are marked with /plugin/; directive
+pub fn is_plugin(tree: &tree_sitter::Tree) -> bool {
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+    let res = root
+        .named_children(&mut cursor)
+        .any(|x| x.kind() == "plugin");
+    res
+}
+
 pub fn current_url() -> Result<Url> {
     let mut err = Error::internal_error();
     let Ok(dst) = std::env::current_dir() else {
@@ -86,3 +130,26 @@ pub trait Leakable {
 pub fn url_exists(uri: &Url) -> bool {
     uri.to_file_path().map(|x| x.exists()).unwrap_or(false)
 }
+
+pub fn parse(text: &str) -> tree_sitter::Tree {
+    let mut parser = tree_sitter::Parser::new();
+    parser
+        .set_language(&tree_sitter_devicetree::LANGUAGE.into())
+        .unwrap();
+    parser.parse(text, None).unwrap()
+}
+
+// Path of reference the node is part of, e.g. "/soc/uart@1000" for &{/soc/uart@1000}
+pub fn path_reference<'a>(node: &tree_sitter::Node, text: &'a str) -> Option<&'a str> {
+    let mut cur = *node;
+    loop {
+        match cur.kind() {
+            "reference" => {
+                let path = cur.named_child(0).filter(|x| x.kind() == "path")?;
+                return path.utf8_text(text.as_bytes()).ok();
+            }
+            "path" | "path_node" | "identifier" | "unit_address" => cur = cur.parent()?,
+            _ => return None,
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use crate::addresses;
+use crate::device_tree::{CellKind, DeviceTree, Property};
+use crate::diagnostics::new_diagnostic;
+use crate::includes_depot::IncludesDepot;
+use crate::interrupts;
+use tower_lsp::lsp_types::{Diagnostic, DiagnosticSeverity, Location, Range, Url};
+
+/*
+ * Semantic checks on top of merged device tree. Most of them mirror dtc warnings, so
+ * they are reported with the same severity.
+ */
+
+/*
+ * Phandle + arguments properties, name of the property in target that defines arguments count
+ * and whether that property is optional. Missing optional property means no arguments.
+ */
+const PHANDLE_PROPERTIES: &[(&str, &str, bool)] = &[
+    ("clocks", "#clock-cells", false),
+    ("resets", "#reset-cells", false),
+    ("interrupts-extended", "#interrupt-cells", false),
+    ("dmas", "#dma-cells", false),
+    ("pwms", "#pwm-cells", false),
+    ("iommus", "#iommu-cells", false),
+    ("phys", "#phy-cells", false),
+    ("mboxes", "#mbox-cells", false),
+    ("power-domains", "#power-domain-cells", false),
+    ("io-channels", "#io-channel-cells", false),
+    ("interconnects", "#interconnect-cells", false),
+    ("thermal-sensors", "#thermal-sensor-cells", false),
+    ("sound-dai", "#sound-dai-cells", false),
+    ("msi-parent", "#msi-cells", true),
+    ("hwlocks", "#hwlock-cells", false),
+    ("nvmem-cells", "#nvmem-cell-cells", true),
+];
+
+pub struct Context<'a> {
+    pub tree: &'a DeviceTree,
+    pub id: &'a IncludesDepot,
+    pub diagnostics: Vec<(Url, Diagnostic)>,
+}
+
+impl Context<'_> {
+    pub fn warn(&mut self, uri: &Url, range: Range, message: String) {
+        let diag = new_diagnostic(range, DiagnosticSeverity::WARNING, message);
+        self.diagnostics.push((uri.clone(), diag));
+    }
+
+    pub fn error(&mut self, uri: &Url, range: Range, message: String) {
+        let diag = new_diagnostic(range, DiagnosticSeverity::ERROR, message);
+        self.diagnostics.push((uri.clone(), diag));
+    }
+}
+
+pub fn check(tree: &DeviceTree, id: &IncludesDepot) -> Vec<(Url, Diagnostic)> {
+    let mut ctx = Context {
+        tree,
+        id,
+        diagnostics: Vec::new(),
+    };
+
+    for (reference, location) in &tree.unresolved {
+        let msg = format!("Unresolved overlay target: {reference}");
+        ctx.error(&location.uri, location.range, msg);
+    }
+
+    for idx in tree.nodes() {
+        check_reg(&mut ctx, idx);
+        check_unit_address(&mut ctx, idx);
+        check_unique_unit_address(&mut ctx, idx);
+        check_ranges(&mut ctx, idx, "ranges");
+        check_ranges(&mut ctx, idx, "dma-ranges");
+        check_interrupts(&mut ctx, idx);
+
+        for property in &tree.node(idx).properties {
+            if let Some((cells_name, optional)) = phandle_cells_name(&property.name) {
+                check_phandle_args(&mut ctx, property, cells_name, optional);
+            }
+        }
+    }
+
+    check_overlaps(&mut ctx);
+
+    ctx.diagnostics
+}
+
+pub fn phandle_cells_name(name: &str) -> Option<(&'static str, bool)> {
+    if (name == "gpios" || name.ends_with("-gpios")) && name != "nr-gpios" {
+        return Some(("#gpio-cells", false));
+    }
+
+    PHANDLE_PROPERTIES
+        .iter()
+        .find(|x| x.0 == name)
+        .map(|x| (x.1, x.2))
+}
+
+fn check_reg(ctx: &mut Context, idx: usize) {
+    let node = ctx.tree.node(idx);
+    let Some(reg) = node.property("reg") else {
+        return;
+    };
+    let Some(cells) = reg.cells() else {
+        return;
+    };
+
+    let address_cells = ctx.tree.address_cells(node.parent, ctx.id);
+    let size_cells = ctx.tree.size_cells(node.parent, ctx.id);
+    let entry = address_cells + size_cells;
+    let n = cells.len() as u64;
+
+    if n == 0 || entry == 0 || !n.is_multiple_of(entry) {
+        let msg = format!(
+            "reg has {n} cells, expected a multiple of {entry} \
+            (#address-cells = <{address_cells}>, #size-cells = <{size_cells}> in parent)"
+        );
+        ctx.warn(&reg.uri, reg.range, msg);
+    }
+}
+
+fn check_ranges(ctx: &mut Context, idx: usize, name: &str) {
+    let node = ctx.tree.node(idx);
+    let Some(ranges) = node.property(name) else {
+        return;
+    };
+    let Some(cells) = ranges.cells() else {
+        return;
+    };
+
+    // Empty ranges means identity mapping
+    if cells.is_empty() {
+        return;
+    }
+
+    let child_address_cells = ctx.tree.address_cells(Some(idx), ctx.id);
+    let parent_address_cells = ctx.tree.address_cells(node.parent, ctx.id);
+    let size_cells = ctx.tree.size_cells(Some(idx), ctx.id);
+    let entry = child_address_cells + parent_address_cells + size_cells;
+    let n = cells.len() as u64;
+
+    if entry == 0 || !n.is_multiple_of(entry) {
+        let msg = format!(
+            "{name} has {n} cells, expected a multiple of {entry} \
+            (child #address-cells = <{child_address_cells}>, \
+            parent #address-cells = <{parent_address_cells}>, \
+            #size-cells = <{size_cells}>)"
+        );
+        ctx.warn(&ranges.uri, ranges.range, msg);
+    }
+}
+
+fn check_phandle_args(ctx: &mut Context, property: &Property, cells_name: &str, optional: bool) {
+    let Some(cells) = property.cells() else {
+        return;
+    };
+
+    let mut i = 0;
+    let mut prev = None;
+    while i < cells.len() {
+        let cell = cells[i];
+        let CellKind::Reference(reference) = &cell.kind else {
+            match (cell.value(&property.uri, ctx.id), prev) {
+                // Zero phandle marks an empty entry
+                (Some(0), _) => {
+                    i += 1;
+                    continue;
+                }
+                (Some(_), Some((reference, expected))) => {
+                    let msg = format!(
+                        "Too many arguments for {reference} in {} ({cells_name} = <{expected}>)",
+                        property.name
+                    );
+                    ctx.warn(&property.uri, cell.range, msg);
+                }
+                _ => (),
+            }
+            return;
+        };
+
+        // Undefined labels are reported separately
+        let Some(target) = ctx.tree.resolve(reference) else {
+            return;
+        };
+
+        let expected = match ctx.tree.node(target).property(cells_name) {
+            Some(x) => x.u32(ctx.id),
+            None if optional => Some(0),
+            None => None,
+        };
+        let Some(expected) = expected else {
+            let msg = format!("{reference} has no {cells_name} property");
+            ctx.warn(&property.uri, cell.range, msg);
+            return;
+        };
+
+        let args: Vec<_> = cells[i + 1..]
+            .iter()
+            .take_while(|x| matches!(x.kind, CellKind::Expression))
+            .collect();
+        let expected = usize::try_from(expected).unwrap_or(usize::MAX);
+
+        if args.len() < expected {
+            let msg = format!(
+                "{} expects {expected} argument(s) ({cells_name} = <{expected}>), got {}",
+                property.name,
+                args.len()
+            );
+            ctx.warn(&property.uri, cell.range, msg);
+            return;
+        }
+
+        prev = Some((reference, expected));
+        i += 1 + expected;
+    }
+}
+
+fn check_unit_address_format(ctx: &mut Context, location: &Location, address: &str) {
+    for component in address.split(',') {
+        let msg = if component.starts_with("0x") || component.starts_with("0X") {
+            "Unit address should not have leading \"0x\""
+        } else if component.len() > 1 && component.starts_with('0') {
+            "Unit address should not have leading zeros"
+        } else if component.chars().any(|x| x.is_ascii_uppercase()) {
+            "Unit address should be in lowercase"
+        } else {
+            continue;
+        };
+        ctx.warn(&location.uri, location.range, msg.to_string());
+        return;
+    }
+}
+
+// Address of the first reg entry, if it can be evaluated
+fn reg_address(ctx: &Context, idx: usize) -> Option<u64> {
+    let node = ctx.tree.node(idx);
+    let reg = node.property("reg")?;
+    let cells = reg.cells()?;
+    let address_cells = usize::try_from(ctx.tree.address_cells(node.parent, ctx.id)).ok()?;
+    if address_cells == 0 || address_cells > 2 || cells.len() < address_cells {
+        return None;
+    }
+
+    let mut res = 0;
+    for cell in &cells[..address_cells] {
+        res = (res << 32) | cell.value(&reg.uri, ctx.id)?;
+    }
+    Some(res)
+}
+
+fn check_unit_address(ctx: &mut Context, idx: usize) {
+    let node = ctx.tree.node(idx);
+    let (Some(_), Some(location)) = (node.parent, node.locations.first()) else {
+        return;
+    };
+
+    // Overlay fragments are not real nodes
+    if node
+        .children
+        .iter()
+        .any(|x| ctx.tree.node(*x).name == "__overlay__")
+    {
+        return;
+    }
+
+    let ranges = node.property("ranges").filter(|x| !x.values.is_empty());
+    let has_reg = node.property("reg").is_some() || ranges.is_some();
+
+    match node.unit_address() {
+        None if has_reg => {
+            let msg = "Node has a reg or ranges property, but no unit address".to_string();
+            ctx.warn(&location.uri, location.range, msg);
+        }
+        Some(_) if !has_reg => {
+            let msg = "Node has a unit address, but no reg or ranges property".to_string();
+            ctx.warn(&location.uri, location.range, msg);
+        }
+        Some(address) => {
+            check_unit_address_format(ctx, location, address);
+
+            let unit_address = u64::from_str_radix(address, 16).ok();
+            match (unit_address, reg_address(ctx, idx)) {
+                (Some(x), Some(reg)) if x != reg => {
+                    let msg =
+                        format!("Unit address does not match reg address, expected \"{reg:x}\"");
+                    ctx.warn(&location.uri, location.range, msg);
+                }
+                _ => (),
+            }
+        }
+        None => (),
+    }
+}
+
+fn check_unique_unit_address(ctx: &mut Context, idx: usize) {
+    let children = &ctx.tree.node(idx).children;
+    for (i, a) in children.iter().enumerate() {
+        let a = ctx.tree.node(*a);
+        let (Some(address), Some(location)) = (a.unit_address(), a.locations.first()) else {
+            continue;
+        };
+
+        let duplicate = children
+            .iter()
+            .enumerate()
+            .find(|(j, x)| *j != i && ctx.tree.node(**x).unit_address() == Some(address));
+
+        if let Some((_, other)) = duplicate {
+            let msg = format!(
+                "Duplicate unit address (also used in node {})",
+                ctx.tree.node(*other).name
+            );
+            let location = location.clone();
+            ctx.warn(&location.uri, location.range, msg);
+        }
+    }
+}
+
+fn check_interrupts(ctx: &mut Context, idx: usize) {
+    let Some(property) = ctx.tree.node(idx).property("interrupts") else {
+        return;
+    };
+
+    if interrupts::interrupt_parent(ctx.tree, idx).is_none() {
+        let msg = "Node has interrupts, but no interrupt parent".to_string();
+        ctx.warn(&property.uri, property.range, msg);
+        return;
+    }
+
+    // Values that can't be split into specifiers, e.g. /bits/ 64 cells or #interrupt-cells
+    // that can't be evaluated, are not checked
+    let Some(specifiers) = interrupts::specifiers(ctx.tree, ctx.id, idx, property) else {
+        return;
+    };
+
+    for specifier in specifiers {
+        // Specifiers that could not be evaluated can't be routed through interrupt-map
+        let evaluated = specifier
+            .cells
+            .iter()
+            .all(|x| x.value(&property.uri, ctx.id).is_some());
+        if specifier.interrupt.is_none() && evaluated {
+            let msg = "No interrupt-controller is reachable for the interrupt".to_string();
+            let range = specifier.cells.first().map_or(property.range, |x| x.range);
+            ctx.warn(&property.uri, range, msg);
+        }
+    }
+}
+
+// Parent nodes of the node, closest first
+fn ancestors(tree: &DeviceTree, idx: usize) -> impl Iterator<Item = usize> + '_ {
+    std::iter::successors(tree.node(idx).parent, |x| tree.node(*x).parent)
+}
+
+fn check_overlaps(ctx: &mut Context) {
+    let tree = ctx.tree;
+    let mut regions = Vec::new();
+    for idx in tree.nodes() {
+        // Reserved memory regions are carved out of memory nodes by design
+        let reserved = ancestors(tree, idx).any(|x| tree.node(x).name == "reserved-memory");
+        if reserved || !tree.is_enabled(idx) {
+            continue;
+        }
+
+        let Some(reg) = tree.node(idx).property("reg") else {
+            continue;
+        };
+        for region in addresses::regions(tree, ctx.id, idx, reg).unwrap_or_default() {
+            if let (Some(start), 1..) = (region.cpu, region.size) {
+                let end = start.saturating_add(region.size);
+                regions.push((start, end, idx, reg, region));
+            }
+        }
+    }
+    regions.sort_by_key(|x| x.0);
+
+    for (i, a) in regions.iter().enumerate() {
+        for b in regions[i + 1..].iter().take_while(|b| b.0 < a.1) {
+            // Nodes may describe parts of their parent's region, e.g. syscon children
+            let related = a.2 == b.2
+                || ancestors(tree, a.2)
+                    .chain(ancestors(tree, b.2))
+                    .any(|x| x == a.2 || x == b.2);
+            if related {
+                continue;
+            }
+
+            for (x, y) in [(a, b), (b, a)] {
+                let (Some(first), Some(last)) = (x.4.cells.first(), x.4.cells.last()) else {
+                    continue;
+                };
+                let msg = format!(
+                    "Memory region {} overlaps with {} ({})",
+                    x.4.describe().unwrap_or_default(),
+                    tree.path(y.2),
+                    y.4.describe().unwrap_or_default()
+                );
+                ctx.warn(&x.3.uri, Range::new(first.range.start, last.range.end), msg);
+            }
+        }
+    }
+}
@@ -1,23 +1,52 @@
+use crate::code_actions;
+use crate::compare;
+use crate::compare::Difference;
+use crate::compiler::Compiler;
 use crate::config::Config;
+use crate::device_tree::DeviceTree;
+use crate::encoding;
+use crate::encoding::{convert_range, offset_position};
+use crate::fdt;
 use crate::file_depot;
 use crate::file_depot::FileDepot;
+use crate::folding;
+use crate::formatting;
+use crate::hover;
 use crate::includes_depot::IncludesDepot;
+use crate::inlay_hints;
+use crate::interrupts;
 use crate::labels_depot::LabelsDepot;
+use crate::macros;
+use crate::macros_depot::MacrosDepot;
+use crate::preprocessor;
 use crate::references_depot::ReferencesDepot;
-use crate::utils::convert_range;
+use crate::selection;
+use crate::trees_depot::TreesDepot;
 use crate::utils::extension_one_of;
+use crate::utils::include_path;
+use crate::utils::is_blob;
 use crate::utils::is_header;
-use crate::utils::url_exists;
+use crate::utils::is_overlay;
+use crate::utils::is_plugin;
+use crate::utils::parse;
+use crate::utils::range_contains;
+use crate::validation;
 use crate::{error, log_message, warn};
-use diagnostics::DiagnosticExt;
 use std::collections::HashMap;
 use std::fs::metadata;
+use std::fs::read;
 use std::fs::read_dir;
 use std::fs::read_to_string;
 use std::path::PathBuf;
+use std::sync::Arc;
 use streaming_iterator::StreamingIterator;
 use tokio::runtime::Handle;
-use tower_lsp::lsp_types::{MessageType, Url};
+use tower_lsp::lsp_types::{
+    CodeAction, CodeActionKind, CodeActionOrCommand, Diagnostic, DocumentChanges, FoldingRange,
+    InlayHint, InlayHintKind, InlayHintLabel, Location, MessageType, OneOf,
+    OptionalVersionedTextDocumentIdentifier, Position, Range, SelectionRange, TextDocumentEdit,
+    TextEdit, Url, WorkspaceEdit,
+};
 use tower_lsp::Client;
 use tree_sitter::Parser;
 use tree_sitter::Query;
@@ -35,6 +64,9 @@ pub struct Workspace {
     pub ld: LabelsDepot,
     pub rd: ReferencesDepot,
     pub id: IncludesDepot,
+    pub md: MacrosDepot,
+    pub cc: Compiler,
+    pub td: TreesDepot,
 }
 
 impl Workspace {
@@ -45,6 +77,9 @@ impl Workspace {
             ld: LabelsDepot::new(&fd),
             rd: ReferencesDepot::new(&fd),
             id: IncludesDepot::new(&fd),
+            md: MacrosDepot::new(&fd),
+            cc: Compiler::new(),
+            td: TreesDepot::new(),
             fd,
             handle,
             client,
@@ -93,19 +128,11 @@ impl Workspace {
             for node in nodes {
                 let label = node.utf8_text(text.as_bytes()).unwrap();
 
-                let label = label.trim_matches('"');
-                let label = label.trim_matches('<');
-                let label = label.trim_matches('>');
-                let mut new_url = uri.join(label).unwrap();
-
-                if !url_exists(&new_url) {
-                    if let Some(tmp) = self.fd.get_real_path(label) {
-                        new_url = tmp;
-                    } else {
-                        warn!("Could not find include: {new_url}");
-                        continue;
-                    }
-                }
+                let label = include_path(label);
+                let Some(new_url) = self.fd.resolve_include(uri, label) else {
+                    warn!("Could not find include: {}", uri.join(label).unwrap());
+                    continue;
+                };
                 v.push(new_url.clone());
                 self.fd.add_include(uri, &new_url);
             }
@@ -143,34 +170,47 @@ impl Workspace {
         let q = Query::new(
             &tree_sitter_devicetree::LANGUAGE.into(),
             "[
-            (preproc_def name: (identifier)@name value: (preproc_arg)@id)
-            (preproc_function_def name: (identifier)@name parameters: (preproc_params) value: (preproc_arg)@id)
+            (preproc_def name: (identifier)@name value: (preproc_arg)? @id)
+            (preproc_function_def name: (identifier)@name parameters: (preproc_params)@params value: (preproc_arg)? @id)
             ]",
         )
         .unwrap();
         let mut matches = cursor.matches(&q, tree.root_node(), text.as_bytes());
         while let Some(m) = matches.next() {
-            let nodes = m
-                .nodes_for_capture_index(0)
-                .zip(m.nodes_for_capture_index(1));
-            for (name, value) in nodes {
+            let params = m.nodes_for_capture_index(2).next().map(|x| {
+                let mut cursor = x.walk();
+                x.named_children(&mut cursor)
+                    .map(|x| x.utf8_text(text.as_bytes()).unwrap().to_string())
+                    .collect::<Vec<_>>()
+            });
+            // Defines without value still matter for #ifdef
+            let value = m
+                .nodes_for_capture_index(1)
+                .next()
+                .map_or("", |x| x.utf8_text(text.as_bytes()).unwrap().trim());
+            for name in m.nodes_for_capture_index(0) {
                 let def_name = name.utf8_text(text.as_bytes()).unwrap();
-                let value = value.utf8_text(text.as_bytes()).unwrap();
-                let value = value.trim_end().trim_start();
+                let range = convert_range(&name.range());
                 self.id
-                    .add_define(def_name, uri, convert_range(&name.range()), value);
+                    .add_define(def_name, uri, range, value, params.clone());
             }
         }
     }
 
+    pub fn process_macros(&self, tree: &Tree, uri: &Url, text: &str) {
+        for (name, range) in macros::uses(tree, text) {
+            self.md.add_use(&name, uri, range);
+        }
+    }
+
     fn handle_single_file(
         &self,
         uri: &Url,
         text: Option<String>,
         includes: &mut Vec<Url>,
-        diagnostics: &mut HashMap<Url, Vec<DiagnosticExt>>,
+        new: &mut Vec<Url>,
     ) {
-        if !extension_one_of(uri, &["dts", "dtsi", "h"]) {
+        if !extension_one_of(uri, &["dts", "dtsi", "dtso", "h", "dtb", "dtbo"]) {
             return;
         }
 
@@ -179,11 +219,19 @@ impl Workspace {
             return;
         };
 
-        let text = match text.map_or(read_to_string(path), Ok) {
-            Ok(x) => x,
-            Err(e) => {
-                warn!("can't read file {}: {}", uri, e.kind());
+        // Blobs are handled as their decompiled source, text from the client is not useful
+        let text = if is_blob(uri) {
+            let Some(blob) = Self::read_blob(uri) else {
                 return;
+            };
+            fdt::render(&blob)
+        } else {
+            match text.map_or(read_to_string(path), Ok) {
+                Ok(x) => x,
+                Err(e) => {
+                    warn!("can't read file {}: {}", uri, e.kind());
+                    return;
+                }
             }
         };
 
@@ -193,9 +241,11 @@ impl Workspace {
                 self.ld.invalidate(uri);
                 self.rd.invalidate(uri);
                 self.id.invalidate(uri);
+                self.md.invalidate(uri);
             }
-            file_depot::InsertResult::Ok => (),
+            file_depot::InsertResult::Ok => new.push(uri.clone()),
         };
+        self.td.invalidate(&self.fd.get_roots(uri));
 
         let mut parser = Parser::new();
         parser
@@ -204,48 +254,857 @@ impl Workspace {
         let tree = parser.parse(&text, None).unwrap();
 
         self.process_defines(&tree, uri, &text);
+        self.process_macros(&tree, uri, &text);
         if is_header(uri) {
             return;
         }
 
         self.process_labels(&tree, uri, &text);
-        // Currently there are too many false positives, this also means that there will be too
-        // much traffic towards client, making it slow when big workspace is fully scanned.
-        if self.config.experimental {
-            let mut t = diagnostics::gather(uri, &tree, &text);
-            let e = diagnostics.entry(uri.clone()).or_default();
-            e.append(&mut t);
-        }
         self.process_references(&tree, uri, &text);
         let mut t = self.process_includes(&tree, uri, &text);
         includes.append(&mut t);
+
+        // Overlay is resolved against base tree, so base is treated as included by overlay
+        let overlay = is_overlay(uri) && is_plugin(&tree);
+        if let (true, Some(base)) = (overlay, self.fd.get_overlay_base()) {
+            self.fd.add_include(uri, &base);
+            includes.push(base);
+        }
+    }
+
+    fn read_blob(uri: &Url) -> Option<fdt::Blob> {
+        let path = uri.to_file_path().ok()?;
+        let data = match read(path) {
+            Ok(x) => x,
+            Err(e) => {
+                warn!("can't read file {}: {}", uri, e.kind());
+                return None;
+            }
+        };
+        match fdt::parse(&data, uri) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                warn!("can't decode blob {}: {}", uri, e);
+                None
+            }
+        }
+    }
+
+    // Source of the blob as it was decompiled when loaded
+    pub fn decompile(&self, uri: &Url) -> Option<String> {
+        if !is_blob(uri) {
+            return None;
+        }
+        if !self.fd.exist(uri) {
+            self.handle_file(uri, None);
+        }
+        self.fd.get_text(uri)
+    }
+
+    // Differences between the merged tree of the source and the tree of the blob. Blob is
+    // compared as it is decoded, not as its decompiled source is parsed back.
+    pub fn compare_blob(&self, source: &Url, blob: &Url) -> Option<Vec<Difference>> {
+        if !is_blob(blob) {
+            return None;
+        }
+        if !self.fd.exist(source) {
+            self.handle_file(source, None);
+        }
+        if !self.fd.exist(source) {
+            return None;
+        }
+
+        let blob = Self::read_blob(blob)?;
+        let source = DeviceTree::build(&self.fd, &self.id, source);
+        Some(compare::compare(&source, &blob.tree, &self.id))
+    }
+
+    // Merged trees of all top-level files the file is part of
+    pub fn device_trees(&self, uri: &Url) -> Vec<Arc<DeviceTree>> {
+        self.fd
+            .get_roots(uri)
+            .iter()
+            .filter(|x| extension_one_of(x, &["dts", "dtso", "dtb", "dtbo"]))
+            .map(|x| {
+                self.td
+                    .get_or_build(x, || DeviceTree::build(&self.fd, &self.id, x))
+            })
+            .collect()
+    }
+
+    pub fn hover(&self, uri: &Url, position: Position) -> Option<String> {
+        self.device_trees(uri)
+            .iter()
+            .find_map(|x| hover::hover(x, &self.id, uri, position))
+    }
+
+    pub fn inlay_hints(&self, uri: &Url, range: Range) -> Vec<InlayHint> {
+        let mut hints = match self.fd.get_text(uri) {
+            Some(text) => inlay_hints::macro_hints(&parse(&text), &text, uri, &self.id),
+            None => Vec::new(),
+        };
+        hints.retain(|x| range_contains(&range, x.0));
+
+        for tree in self.device_trees(uri) {
+            for hint in inlay_hints::inlay_hints(&tree, &self.id, uri) {
+                if range_contains(&range, hint.0) && !hints.contains(&hint) {
+                    hints.push(hint);
+                }
+            }
+        }
+
+        hints
+            .into_iter()
+            .map(|(position, label)| {
+                // Parameter names go before the value, everything else after it
+                let parameter = label.ends_with(':');
+                InlayHint {
+                    position: self.fd.position_to_client(uri, position),
+                    label: InlayHintLabel::String(label),
+                    kind: parameter.then_some(InlayHintKind::PARAMETER),
+                    text_edits: None,
+                    tooltip: None,
+                    padding_left: Some(!parameter),
+                    padding_right: Some(parameter),
+                    data: None,
+                }
+            })
+            .collect()
+    }
+
+    // Edits renaming the macro in every file that sees its definition
+    pub fn rename_macro(
+        &self,
+        uri: &Url,
+        name: &str,
+        new_name: &str,
+    ) -> Result<HashMap<Url, Vec<TextEdit>>, String> {
+        let Some(define) = self.id.find_define(uri, name) else {
+            return Err(format!("Unknown macro: {name}"));
+        };
+        if !macros::is_identifier(new_name) {
+            return Err(format!("Invalid macro name: {new_name}"));
+        }
+
+        let mut files = self.fd.get_component(&define.uri);
+        files.push(define.uri.clone());
+
+        let mut res = HashMap::new();
+        for file in files {
+            // Conflicts are checked in every file, since each of them may see different macros
+            if self.id.find_define(&file, new_name).is_some() {
+                return Err(format!("Macro {new_name} is already defined"));
+            }
+
+            // Same name may refer to a different macro in this file
+            let same = self
+                .id
+                .find_define(&file, name)
+                .is_some_and(|x| x.uri == define.uri && x.range == define.range);
+            let Some(text) = self.fd.get_text(&file).filter(|_| same) else {
+                continue;
+            };
+
+            let edits: Vec<TextEdit> = macros::occurrences(&parse(&text), &text, name)
+                .into_iter()
+                .map(|x| TextEdit::new(x, new_name.to_string()))
+                .collect();
+            if !edits.is_empty() {
+                res.insert(file, edits);
+            }
+        }
+        Ok(res)
+    }
+
+    /*
+     * Build workspace edit out of edits computed against current buffers. Nothing is changed
+     * here, index is updated when client applies the edit and sends didChange. Overlapping
+     * edits fail the whole edit, since applying only some of them leaves files inconsistent.
+     */
+    pub fn workspace_edit(
+        &self,
+        edits: HashMap<Url, Vec<TextEdit>>,
+    ) -> Result<WorkspaceEdit, String> {
+        let mut edits: Vec<_> = edits.into_iter().collect();
+        edits.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut res = Vec::new();
+        for (uri, mut edits) in edits {
+            // Bottom to top, so that edits can be applied one by one without shifting others
+            edits.sort_by_key(|x| std::cmp::Reverse((x.range.start, x.range.end)));
+            edits.dedup();
+
+            let mut ordered: Vec<TextEdit> = Vec::new();
+            for edit in edits {
+                if ordered
+                    .last()
+                    .is_some_and(|x| edit.range.end > x.range.start)
+                {
+                    let line = edit.range.start.line + 1;
+                    return Err(format!("Overlapping edits in {uri} at line {line}"));
+                }
+                ordered.push(edit);
+            }
+
+            let edits = ordered
+                .into_iter()
+                .map(|x| {
+                    let range = self.fd.range_to_client(&uri, x.range);
+                    OneOf::Left(TextEdit::new(range, x.new_text))
+                })
+                .collect();
+
+            res.push(TextDocumentEdit {
+                text_document: OptionalVersionedTextDocumentIdentifier {
+                    version: self.fd.get_version(&uri),
+                    uri,
+                },
+                edits,
+            });
+        }
+
+        Ok(WorkspaceEdit {
+            changes: None,
+            document_changes: Some(DocumentChanges::Edits(res)),
+            change_annotations: None,
+        })
+    }
+
+    // Edits formatting the whole document or statements inside of the range
+    pub fn format(&self, uri: &Url, range: Option<Range>) -> Option<Vec<TextEdit>> {
+        if !extension_one_of(uri, &["dts", "dtsi", "dtso"]) {
+            return None;
+        }
+        let text = self.fd.get_text(uri)?;
+        let tree = parse(&text);
+
+        let edits = if let Some(range) = range {
+            formatting::format_range(&tree, &text, range)?
+        } else {
+            let formatted = formatting::format(&tree, &text)?;
+            let end = offset_position(Position::new(0, 0), &text, text.len());
+            vec![(Range::new(Position::new(0, 0), end), formatted)]
+        };
+
+        let res = edits
+            .into_iter()
+            .filter(|(range, new_text)| {
+                let start = encoding::offset(&text, range.start);
+                let end = encoding::offset(&text, range.end);
+                text[start..end] != *new_text
+            })
+            .map(|(range, new_text)| TextEdit::new(self.fd.range_to_client(uri, range), new_text))
+            .collect();
+        Some(res)
+    }
+
+    pub fn folding_ranges(&self, uri: &Url) -> Option<Vec<FoldingRange>> {
+        if !extension_one_of(uri, &["dts", "dtsi", "dtso", "h"]) {
+            return None;
+        }
+        let text = self.fd.get_text(uri)?;
+        Some(folding::folding_ranges(&parse(&text), &text))
+    }
+
+    pub fn selection_ranges(
+        &self,
+        uri: &Url,
+        positions: &[Position],
+    ) -> Option<Vec<SelectionRange>> {
+        if !extension_one_of(uri, &["dts", "dtsi", "dtso", "h"]) {
+            return None;
+        }
+        let text = self.fd.get_text(uri)?;
+        let tree = parse(&text);
+
+        let res = positions
+            .iter()
+            .map(|x| {
+                let mut res: Option<SelectionRange> = None;
+                for range in selection::selection_ranges(&tree, *x).into_iter().rev() {
+                    res = Some(SelectionRange {
+                        range: self.fd.range_to_client(uri, range),
+                        parent: res.map(Box::new),
+                    });
+                }
+                // Position outside of the document
+                res.unwrap_or(SelectionRange {
+                    range: self.fd.range_to_client(uri, Range::new(*x, *x)),
+                    parent: None,
+                })
+            })
+            .collect();
+        Some(res)
+    }
+
+    // Text of the range with macros expanded, empty range stands for the enclosing property
+    // or the line if there is no property
+    pub fn expand_macros(&self, uri: &Url, range: Range) -> Option<String> {
+        let text = self.fd.get_text(uri)?;
+        let range = if range.start == range.end {
+            let tree = parse(&text);
+            let point = encoding::point(range.start);
+            let mut cur = tree.root_node().descendant_for_point_range(point, point);
+            while let Some(x) = cur.filter(|x| x.kind() != "property") {
+                cur = x.parent();
+            }
+            cur.map_or_else(
+                || {
+                    Range::new(
+                        Position::new(range.start.line, 0),
+                        Position::new(range.start.line + 1, 0),
+                    )
+                },
+                |x| convert_range(&x.range()),
+            )
+        } else {
+            range
+        };
+
+        let start = encoding::offset(&text, range.start);
+        let end = encoding::offset(&text, range.end);
+        let source = text.get(start..end)?.trim_end_matches('\n');
+        Some(macros::expand(source, &|x| self.id.find_macro(uri, x)))
+    }
+
+    pub fn code_actions(
+        &self,
+        uri: &Url,
+        range: Range,
+        diagnostics: &[Diagnostic],
+    ) -> Vec<CodeActionOrCommand> {
+        let Some(text) = self.fd.get_text(uri) else {
+            return Vec::new();
+        };
+        let tree = parse(&text);
+
+        let mut res = self.include_actions(uri, range, diagnostics, &tree, &text);
+        res.extend(self.node_actions(uri, range, &tree, &text));
+        res.into_iter()
+            .map(CodeActionOrCommand::CodeAction)
+            .collect()
+    }
+
+    // Quick fixes that include a header defining an unresolved macro
+    fn include_actions(
+        &self,
+        uri: &Url,
+        range: Range,
+        diagnostics: &[Diagnostic],
+        tree: &Tree,
+        text: &str,
+    ) -> Vec<CodeAction> {
+        let mut res = Vec::new();
+        let mut seen = Vec::new();
+        for (name, macro_range) in code_actions::unresolved_macros(uri, tree, text, &self.id, range)
+        {
+            let client_range = self.fd.range_to_client(uri, macro_range);
+            let fixes: Vec<Diagnostic> = diagnostics
+                .iter()
+                .filter(|x| {
+                    x.range == client_range && x.message == format!("Unresolved macro: {name}")
+                })
+                .cloned()
+                .collect();
+
+            let paths: Vec<String> = self
+                .id
+                .find_headers(&name)
+                .iter()
+                .filter_map(|x| self.fd.include_directive_path(uri, x))
+                .collect();
+
+            for path in &paths {
+                let title = format!("Add #include {path}");
+                if seen.contains(&title) {
+                    continue;
+                }
+                seen.push(title.clone());
+
+                let (position, new_text) = code_actions::include_edit(tree, path);
+                let edit = TextEdit::new(Range::new(position, position), new_text);
+                let edits = HashMap::from([(uri.clone(), vec![edit])]);
+                let Ok(edit) = self.workspace_edit(edits) else {
+                    continue;
+                };
+
+                res.push(CodeAction {
+                    title,
+                    kind: Some(CodeActionKind::QUICKFIX),
+                    diagnostics: Some(fixes.clone()),
+                    edit: Some(edit),
+                    is_preferred: Some(paths.len() == 1),
+                    ..Default::default()
+                });
+            }
+        }
+        res
+    }
+
+    // Label for a new node that is not used anywhere in the trees that include the file
+    fn new_label(&self, uri: &Url, name: &str, address: Option<&str>) -> String {
+        let trees = self.device_trees(uri);
+        code_actions::label_name(name, address, |x| {
+            !self.ld.find_label(uri, x).is_empty()
+                || trees.iter().any(|t| t.find_label(x).is_some())
+        })
+    }
+
+    // Refactorings with overlapping edits are not offered
+    fn refactoring(
+        &self,
+        title: String,
+        kind: CodeActionKind,
+        edits: HashMap<Url, Vec<TextEdit>>,
+    ) -> Option<CodeAction> {
+        Some(CodeAction {
+            title,
+            kind: Some(kind),
+            edit: Some(self.workspace_edit(edits).ok()?),
+            ..Default::default()
+        })
+    }
+
+    // Refactorings of the node under cursor
+    fn node_actions(&self, uri: &Url, range: Range, tree: &Tree, text: &str) -> Vec<CodeAction> {
+        let Some(node) = code_actions::node_at(tree, range.start) else {
+            return Vec::new();
+        };
+        let Some(name) = node.child_by_field_name("name") else {
+            return Vec::new();
+        };
+        let name_text = name.utf8_text(text.as_bytes()).unwrap();
+        let address = node
+            .child_by_field_name("address")
+            .filter(|x| x.kind() == "unit_address")
+            .map(|x| x.utf8_text(text.as_bytes()).unwrap());
+
+        let mut res = Vec::new();
+        if let Some((title, edit)) = code_actions::status_edit(&node, text) {
+            let edits = HashMap::from([(uri.clone(), vec![edit])]);
+            res.extend(self.refactoring(title, CodeActionKind::REFACTOR_REWRITE, edits));
+        }
+
+        // Label of the node, new labels are added in front of the node name
+        let mut label = node
+            .child_by_field_name("label")
+            .or(name.child_by_field_name("label"))
+            .map(|x| (x.utf8_text(text.as_bytes()).unwrap().to_string(), None));
+        if label.is_none() && name.kind() == "identifier" && name_text != "/" {
+            let new = self.new_label(uri, name_text, address);
+            let position = convert_range(&name.range()).start;
+            let edit = TextEdit::new(Range::new(position, position), format!("{new}: "));
+
+            let title = format!("Add label {new}");
+            let edits = HashMap::from([(uri.clone(), vec![edit.clone()])]);
+            res.extend(self.refactoring(title, CodeActionKind::REFACTOR_REWRITE, edits));
+            label = Some((new, Some(edit)));
+        }
+
+        if let Some(label) = label {
+            if extension_one_of(uri, &["dtsi"]) {
+                res.extend(self.extract_actions(uri, &node, range, text, label));
+            }
+        } else if name.kind() == "reference" {
+            res.extend(self.path_override_action(uri, &name, text));
+        }
+        res
+    }
+
+    // Override by path is replaced with label, which is added to the target if needed
+    fn path_override_action(
+        &self,
+        uri: &Url,
+        name: &tree_sitter::Node,
+        text: &str,
+    ) -> Option<CodeAction> {
+        let path = name.named_child(0).filter(|x| x.kind() == "path")?;
+        let path = path.utf8_text(text.as_bytes()).unwrap();
+        let target = self.device_trees(uri).into_iter().find_map(|t| {
+            let idx = t.find_path(path).filter(|x| *x != DeviceTree::ROOT)?;
+            Some(t.node(idx).clone())
+        })?;
+
+        let mut edits: HashMap<Url, Vec<TextEdit>> = HashMap::new();
+        let label = if let Some(x) = target.labels.first() {
+            x.clone()
+        } else {
+            let location = target.locations.first()?;
+            let new = self.new_label(uri, target.base_name(), target.unit_address());
+            let position = location.range.start;
+            let edit = TextEdit::new(Range::new(position, position), format!("{new}: "));
+            edits.entry(location.uri.clone()).or_default().push(edit);
+            new
+        };
+
+        let edit = TextEdit::new(convert_range(&name.range()), format!("&{label}"));
+        edits.entry(uri.clone()).or_default().push(edit);
+        let title = format!("Convert to &{label} override");
+        self.refactoring(title, CodeActionKind::REFACTOR_REWRITE, edits)
+    }
+
+    // Board specific properties are moved from SoC file to overrides in the boards including it
+    fn extract_actions(
+        &self,
+        uri: &Url,
+        node: &tree_sitter::Node,
+        range: Range,
+        text: &str,
+        (label, label_edit): (String, Option<TextEdit>),
+    ) -> Vec<CodeAction> {
+        let selected: Vec<_> = code_actions::properties(node)
+            .into_iter()
+            .filter(|x| {
+                let r = convert_range(&x.range());
+                if range.start == range.end {
+                    range_contains(&r, range.start)
+                } else {
+                    r.start < range.end && range.start < r.end
+                }
+            })
+            .collect();
+        if selected.is_empty() {
+            return Vec::new();
+        }
+
+        let mut lines = String::new();
+        for x in &selected {
+            lines += "\t";
+            lines += x.utf8_text(text.as_bytes()).unwrap();
+            lines += "\n";
+        }
+        let mut removals: Vec<TextEdit> = code_actions::removal_ranges(&selected, text)
+            .into_iter()
+            .map(|x| TextEdit::new(x, String::new()))
+            .collect();
+        removals.extend(label_edit);
+
+        let mut res = Vec::new();
+        for root in self.fd.get_roots(uri) {
+            let Some(root_text) = self.fd.get_text(&root) else {
+                continue;
+            };
+            if !extension_one_of(&root, &["dts"]) {
+                continue;
+            }
+
+            let root_tree = parse(&root_text);
+            let edit = code_actions::override_insert(&root_tree, &root_text, &label, &lines)
+                .unwrap_or_else(|| {
+                    let end = offset_position(Position::new(0, 0), &root_text, root_text.len());
+                    let new_text = format!("\n&{label} {{\n{lines}}};\n");
+                    TextEdit::new(Range::new(end, end), new_text)
+                });
+
+            let file = root
+                .path_segments()
+                .and_then(Iterator::last)
+                .unwrap_or_default();
+            let title = format!("Move properties to &{label} in {file}");
+            let edits =
+                HashMap::from([(uri.clone(), removals.clone()), (root.clone(), vec![edit])]);
+            res.extend(self.refactoring(title, CodeActionKind::REFACTOR_EXTRACT, edits));
+        }
+        res
+    }
+
+    // Uses of the macro in every file that sees its definition
+    pub fn find_macro_uses(
+        &self,
+        uri: &Url,
+        name: &str,
+        include_declaration: bool,
+    ) -> Option<Vec<Location>> {
+        let define = self.id.find_define(uri, name)?;
+
+        let mut res = Vec::new();
+        if include_declaration {
+            res.push(Location::new(define.uri.clone(), define.range));
+        }
+
+        let mut uses = self.md.find_uses(&define.uri, name);
+        uses.sort_by_key(|x| (x.uri.clone(), x.range.start));
+        for x in uses {
+            // Same name may refer to a different macro in this file
+            let same = self
+                .id
+                .find_define(&x.uri, name)
+                .is_some_and(|x| x.uri == define.uri && x.range == define.range);
+            if same {
+                res.push(Location::new(x.uri, x.range));
+            }
+        }
+        Some(res)
+    }
+
+    // References to the node at the path, both by its labels and by path
+    pub fn find_path_references(
+        &self,
+        uri: &Url,
+        path: &str,
+        include_declaration: bool,
+    ) -> Vec<Location> {
+        self.find_node_references(uri, include_declaration, |x| x.find_path(path))
+    }
+
+    // References to the node with the label, same as for its path. Labels that are not part of
+    // any tree only have references by label.
+    pub fn find_label_references(
+        &self,
+        uri: &Url,
+        label: &str,
+        include_declaration: bool,
+    ) -> Vec<Location> {
+        let res = self.find_node_references(uri, include_declaration, |x| x.find_label(label));
+        if !res.is_empty() {
+            return res;
+        }
+
+        let mut res = Vec::new();
+        if include_declaration {
+            for x in self.ld.find_label(uri, label) {
+                res.push(Location::new(x.uri, x.range));
+            }
+        }
+        for x in self.rd.find_references(uri, label) {
+            res.push(Location::new(x.uri, x.range));
+        }
+        res
+    }
+
+    fn find_node_references<F>(
+        &self,
+        uri: &Url,
+        include_declaration: bool,
+        find: F,
+    ) -> Vec<Location>
+    where
+        F: Fn(&DeviceTree) -> Option<usize>,
+    {
+        let mut files = self.fd.get_component(uri);
+        files.push(uri.clone());
+
+        let mut res = Vec::new();
+        for tree in self.device_trees(uri) {
+            let Some(idx) = find(&tree) else {
+                continue;
+            };
+            let node = tree.node(idx);
+
+            // Declaration of the node is its name and labels
+            if include_declaration {
+                res.extend(node.locations.first().cloned());
+                for label in &node.labels {
+                    for x in self.ld.find_label(uri, label) {
+                        res.push(Location::new(x.uri, x.range));
+                    }
+                }
+            }
+            for label in &node.labels {
+                for x in self.rd.find_references(uri, label) {
+                    res.push(Location::new(x.uri, x.range));
+                }
+            }
+
+            for file in &files {
+                let Some(text) = self.fd.get_text(file) else {
+                    continue;
+                };
+                diagnostics::walk(parse(&text).root_node(), &mut |x| {
+                    if x.kind() != "path" || x.parent().is_none_or(|x| x.kind() != "reference") {
+                        return;
+                    }
+                    let path = x.utf8_text(text.as_bytes()).unwrap();
+                    if tree.find_path(path) == Some(idx) {
+                        res.push(Location::new(file.clone(), convert_range(&x.range())));
+                    }
+                });
+            }
+        }
+
+        res.sort_by_key(|x| (x.uri.clone(), x.range.start));
+        res.dedup();
+        res
+    }
+
+    pub fn interrupt_controller(&self, uri: &Url, position: Position) -> Option<Location> {
+        self.device_trees(uri).iter().find_map(|x| {
+            let idx = interrupts::controller_at(x, &self.id, uri, position)?;
+            x.node(idx).locations.first().cloned()
+        })
+    }
+
+    pub fn semantic_diagnostics(&self, uri: &Url) -> Vec<Diagnostic> {
+        if !extension_one_of(uri, &["dts", "dtsi", "dtso", "h"]) {
+            return Vec::new();
+        }
+
+        let Some(text) = self.fd.get_text(uri) else {
+            return Vec::new();
+        };
+        let tree = parse(&text);
+
+        let inactive = preprocessor::inactive_regions(&tree, uri, &text, &self.id);
+        let mut res = diagnostics::recursive_macros(uri, &tree, &text, &self.id);
+        if !is_header(uri) {
+            res.append(&mut self.source_diagnostics(uri, &tree, &text));
+        }
+
+        // Problems in code skipped by preprocessor are not reported
+        res.retain(|x| {
+            !inactive
+                .iter()
+                .any(|r| r.start <= x.range.start && x.range.end <= r.end)
+        });
+        res.extend(inactive.into_iter().map(diagnostics::inactive_region));
+
+        for diag in self.cc.diagnostics(uri) {
+            if !res.contains(&diag) {
+                res.push(diag);
+            }
+        }
+        res
+    }
+
+    fn source_diagnostics(&self, uri: &Url, tree: &Tree, text: &str) -> Vec<Diagnostic> {
+        let mut res = Vec::new();
+
+        // Without base tree labels in overlays are resolved only when overlay is applied
+        let check_labels = !is_plugin(tree) || self.fd.get_overlay_base().is_some();
+        res.append(&mut diagnostics::undefined_symbols(
+            uri,
+            tree,
+            text,
+            &self.ld,
+            &self.id,
+            check_labels,
+        ));
+        res.append(&mut diagnostics::invalid_values(uri, tree, text, &self.id));
+
+        for tree in self.device_trees(uri) {
+            for diag in diagnostics::duplicate_labels(uri, &tree) {
+                if !res.iter().any(|x| x.range == diag.range) {
+                    res.push(diag);
+                }
+            }
+            for (url, diag) in validation::check(&tree, &self.id) {
+                if url == *uri && !res.contains(&diag) {
+                    res.push(diag);
+                }
+            }
+        }
+        res
     }
 
     pub fn handle_file(&self, uri: &Url, text: Option<String>) {
+        self.process_file(uri, text, true);
+    }
+
+    /*
+     * Semantic checks build merged trees of every top-level file the file is part of, so they
+     * are run only for the handled file and its roots, and not at all during bulk scans. Other
+     * files read for the first time get syntax diagnostics only.
+     */
+    fn process_file(&self, uri: &Url, text: Option<String>, semantic: bool) {
         let mut includes: Vec<Url> = Vec::new();
-        let mut diagnostics: HashMap<Url, Vec<DiagnosticExt>> = HashMap::new();
+        let mut new: Vec<Url> = Vec::new();
 
-        self.handle_single_file(uri, text, &mut includes, &mut diagnostics);
+        self.handle_single_file(uri, text, &mut includes, &mut new);
         while let Some(new_url) = includes.pop() {
-            self.handle_single_file(&new_url, None, &mut includes, &mut diagnostics);
+            self.handle_single_file(&new_url, None, &mut includes, &mut new);
         }
 
-        if !diagnostics.is_empty() {
-            if let Some(client) = self.client.clone() {
-                for (url, v) in diagnostics {
-                    let v = v
-                        .iter()
-                        .filter(|x| x.verify(&self.id))
-                        .map(|x| x.diag.clone())
-                        .collect();
+        let Some(client) = self.client.clone() else {
+            return;
+        };
 
-                    let client = client.clone();
-                    self.handle.spawn(async move {
-                        client.publish_diagnostics(url, v, None).await;
-                    });
-                }
+        let mut checked = Vec::new();
+        if semantic {
+            checked.push(uri.clone());
+            checked.extend(self.fd.get_roots(uri).into_iter().filter(|x| x != uri));
+        }
+        for url in &checked {
+            let v = self.diagnostics(url, true);
+            self.publish(&client, url.clone(), v);
+        }
+
+        for url in new.into_iter().filter(|x| !checked.contains(x)) {
+            let v = self.diagnostics(&url, false);
+            // Always publish for the handled file to clear stale diagnostics
+            if v.is_empty() && url != *uri {
+                continue;
+            }
+            self.publish(&client, url, v);
+        }
+    }
+
+    // Everything that is published for the file, semantic checks are optional
+    fn diagnostics(&self, uri: &Url, semantic: bool) -> Vec<Diagnostic> {
+        let mut res = Vec::new();
+
+        // Currently there are too many false positives, this also means that there will be too
+        // much traffic towards client, making it slow when big workspace is fully scanned.
+        if self.config.experimental && !is_header(uri) {
+            if let Some(text) = self.fd.get_text(uri) {
+                res = diagnostics::gather(uri, &parse(&text), &text)
+                    .into_iter()
+                    .filter(|x| x.verify(&self.id))
+                    .map(|x| x.diag)
+                    .collect();
+            }
+        }
+
+        if semantic {
+            res.append(&mut self.semantic_diagnostics(uri));
+        }
+        res
+    }
+
+    fn publish(&self, client: &Client, url: Url, mut v: Vec<Diagnostic>) {
+        for diag in &mut v {
+            diag.range = self.fd.range_to_client(&url, diag.range);
+            for x in diag.related_information.iter_mut().flatten() {
+                x.location = self.fd.location_to_client(x.location.clone());
             }
         }
+
+        let client = client.clone();
+        self.handle.spawn(async move {
+            client.publish_diagnostics(url, v, None).await;
+        });
+    }
+
+    // Run dtc on all top-level files the file is part of and publish its diagnostics
+    pub async fn compile(&self, uri: &Url) {
+        if !self.cc.is_enabled() {
+            return;
+        }
+        let Some(root) = self.fd.get_root_dir().and_then(|x| x.to_file_path().ok()) else {
+            return;
+        };
+
+        let mut changed = Vec::new();
+        for x in self.fd.get_roots(uri) {
+            if !extension_one_of(&x, &["dts", "dtso"]) {
+                continue;
+            }
+            let Ok(file) = x.to_file_path() else {
+                continue;
+            };
+            let defines = self.id.predefined_flags(&x);
+            let results = self.cc.run(&root, &file, &defines).await;
+            changed.append(&mut self.cc.set_results(&x, results));
+        }
+
+        let Some(client) = self.client.clone() else {
+            return;
+        };
+        changed.sort();
+        changed.dedup();
+        for url in changed {
+            let v = self.diagnostics(&url, true);
+            self.publish(&client, url, v);
+        }
     }
 
     async fn handle_files<I>(&self, input_files: I)
@@ -266,7 +1125,7 @@ impl Workspace {
 
             let me = self.clone();
             let handle = tokio::spawn(async move {
-                me.handle_file(&u, None);
+                me.process_file(&u, None, false);
             });
             handles.push(handle);
         }
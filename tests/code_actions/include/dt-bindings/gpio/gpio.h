@@ -0,0 +1,2 @@
+#define GPIO_ACTIVE_HIGH 0
+#define GPIO_ACTIVE_LOW 1
@@ -0,0 +1,2 @@
+#define GPIO_ACTIVE_LOW 1
+#define PIN(port, n) (((port) << 4) | (n))
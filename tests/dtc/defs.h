@@ -0,0 +1,2 @@
+#define MODEL "board"
+#define SERIAL_BASE 0x1000
@@ -0,0 +1,8 @@
+#define PIN(port, num) (((port) << 8) | (num))
+#define PINMUX(port, num, func) (PIN(port, num) | ((func) << 16))
+#define PORT_A 0
+#define FUNC_UART 3
+#define NAME(x) #x
+#define CONCAT(a, b) a ## b
+#define UART_BASE 0x4000
+#define SELF (SELF + 1)
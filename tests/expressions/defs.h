@@ -0,0 +1,13 @@
+#ifndef DEFS_H
+#define DEFS_H
+
+#define BASE 0x1000
+#define STRIDE (1 << 4)
+#define REG(n) (BASE + (n) * STRIDE)
+
+#define LOOP_A LOOP_B
+#define LOOP_B (LOOP_A + 1)
+
+#define CAT(a, b) a ## b
+
+#endif
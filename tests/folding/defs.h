@@ -0,0 +1 @@
+#define WITH_SPI
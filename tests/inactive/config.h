@@ -0,0 +1,7 @@
+#ifndef CONFIG_H
+#define CONFIG_H
+
+#define HAS_UART
+#define REVISION 2
+
+#endif
@@ -0,0 +1,5 @@
+#define GPIO_ACTIVE_LOW 1
+#define CLK_MAIN 3
+#define CLK_UART CLK_MAIN
+#define AF7 0x7
+#define STM32_PINMUX(port, line, mode) ((((port) - 'A') * 16 + (line)) << 8 | (mode))
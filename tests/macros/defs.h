@@ -0,0 +1,3 @@
+#define CLK_MAIN 3
+#define CLK_UART CLK_MAIN /* CLK_MAIN */
+#define CLK_SPI 4
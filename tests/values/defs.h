@@ -0,0 +1,2 @@
+#define BASE 0x1000
+#define ZERO 0